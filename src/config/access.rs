@@ -0,0 +1,162 @@
+use {serde::Deserialize, std::net::IpAddr};
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(try_from = "String")]
+/// A range of ip addresses in CIDR notation, such as `192.168.1.0/24` or
+/// `2001:db8::/32`. A bare address matches only itself, and the keyword `all`
+/// matches every address.
+pub enum Cidr {
+    /// Matches every address
+    All,
+    /// Matches addresses sharing the first `prefix` bits with the network
+    Network(IpAddr, u8),
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        if s == "all" {
+            return Ok(Self::All);
+        }
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s.as_str(), None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("Invalid address in \"{s}\": {e}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => match p.parse::<u8>() {
+                Ok(p) if p <= max => p,
+                _ => return Err(format!("Invalid prefix length in \"{s}\"")),
+            },
+            None => max,
+        };
+        Ok(Self::Network(addr, prefix))
+    }
+}
+
+impl Cidr {
+    /// Whether `ip` falls within this range. Ipv4 addresses which have been
+    /// mapped into ipv6, as happens with a dual stack listener, are compared
+    /// as ipv4.
+    #[must_use]
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self, ip.to_canonical()) {
+            (Self::All, _) => true,
+            (Self::Network(IpAddr::V4(net), prefix), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(*prefix)).unwrap_or(0);
+                u32::from(*net) & mask == u32::from(ip) & mask
+            }
+            (Self::Network(IpAddr::V6(net), prefix), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(*prefix)).unwrap_or(0);
+                u128::from(*net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+/// A rule allowing or denying access to clients from a list of ranges
+pub enum AccessRule {
+    /// Clients within any of these ranges are allowed
+    Allow(Vec<Cidr>),
+    /// Clients within any of these ranges are denied
+    Deny(Vec<Cidr>),
+}
+
+impl AccessRule {
+    /// Checks an ordered list of rules, where the first rule containing `ip`
+    /// decides whether it is allowed. Returns `None` if no rule matches.
+    #[must_use]
+    pub fn evaluate(rules: &[Self], ip: IpAddr) -> Option<bool> {
+        rules.iter().find_map(|rule| match rule {
+            Self::Allow(ranges) if ranges.iter().any(|r| r.contains(ip)) => Some(true),
+            Self::Deny(ranges) if ranges.iter().any(|r| r.contains(ip)) => Some(false),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        Cidr::try_from(String::from(s)).unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4() {
+        let net = cidr("192.168.1.0/24");
+        assert!(net.contains(ip("192.168.1.0")));
+        assert!(net.contains(ip("192.168.1.255")));
+        assert!(!net.contains(ip("192.168.2.1")));
+        // Mapped into ipv6 by a dual stack listener
+        assert!(net.contains(ip("::ffff:192.168.1.7")));
+        assert!(!net.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn ipv6() {
+        let net = cidr("2001:db8::/32");
+        assert!(net.contains(ip("2001:db8:ffff::1")));
+        assert!(!net.contains(ip("2001:db9::1")));
+        assert!(!net.contains(ip("192.168.1.1")));
+    }
+
+    #[test]
+    fn prefix_bounds() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(cidr("all").contains(ip("::1")));
+        let host = cidr("10.0.0.1/32");
+        assert!(host.contains(ip("10.0.0.1")));
+        assert!(!host.contains(ip("10.0.0.2")));
+        let host = cidr("2001:db8::1/128");
+        assert!(host.contains(ip("2001:db8::1")));
+        assert!(!host.contains(ip("2001:db8::2")));
+        // A bare address matches only itself
+        let bare = cidr("10.0.0.1");
+        assert!(bare.contains(ip("10.0.0.1")));
+        assert!(!bare.contains(ip("10.0.0.2")));
+    }
+
+    #[test]
+    fn malformed() {
+        for s in [
+            "",
+            "10.0.0",
+            "10.0.0.1/33",
+            "2001:db8::/129",
+            "10.0.0.1/",
+            "10.0.0.1/-1",
+            "10.0.0.1/8/8",
+            "example.com/8",
+            "All",
+        ] {
+            assert!(Cidr::try_from(String::from(s)).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn first_match_decides() {
+        let rules = [
+            AccessRule::Deny(vec![cidr("10.0.0.1")]),
+            AccessRule::Allow(vec![cidr("10.0.0.0/8")]),
+            AccessRule::Deny(vec![cidr("all")]),
+        ];
+        assert_eq!(AccessRule::evaluate(&rules, ip("10.0.0.1")), Some(false));
+        assert_eq!(AccessRule::evaluate(&rules, ip("10.0.0.2")), Some(true));
+        assert_eq!(AccessRule::evaluate(&rules, ip("192.0.2.1")), Some(false));
+        assert_eq!(AccessRule::evaluate(&rules[..2], ip("192.0.2.1")), None);
+        assert_eq!(AccessRule::evaluate(&[], ip("192.0.2.1")), None);
+    }
+}
@@ -1,19 +1,30 @@
 #![allow(clippy::unsafe_derive_deserialize, clippy::module_name_repetitions)]
 use {
+    regex::Regex,
     serde::Deserialize,
     std::{
+        borrow::Cow,
         collections::HashMap,
         ffi::CString,
-        fs,
+        fmt, fs,
         io::{Error, ErrorKind},
-        path::PathBuf,
+        net::IpAddr,
+        path::{Path, PathBuf},
     },
 };
 
+/// Ip based access control
+mod access;
+/// Rules rewriting request paths
+mod rewrite;
 /// A name based Virtual Host
 mod server;
 
-pub use server::{Directive, Server};
+pub use {
+    access::{AccessRule, Cidr},
+    rewrite::{Pattern, Rewrite},
+    server::{Directive, ErrorPage, Listing, Server, SortBy},
+};
 
 #[derive(Deserialize)]
 pub struct Address {
@@ -32,6 +43,217 @@ impl Default for Address {
     }
 }
 
+#[derive(Deserialize)]
+/// Settings for the optional Gopher listener
+pub struct Gopher {
+    /// The ip address and port to bind to
+    pub address: Address,
+    /// The virtual host whose document tree will be served over Gopher
+    pub vhost: String,
+    /// The hostname to advertise in generated gophermaps
+    pub hostname: String,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+/// Limits on how much of the server a single client may use
+pub struct RateLimit {
+    /// The number of requests per second a client may make on average
+    pub requests_per_second: f64,
+    /// The number of requests a client may make in a burst before it is
+    /// limited to `requests_per_second`
+    pub burst: u32,
+    /// The maximum number of connections a client may have open at once
+    pub max_connections: Option<usize>,
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq)]
+/// How often log files are rotated
+pub enum Interval {
+    /// At the start of every hour
+    Hourly,
+    /// At midnight, UTC
+    Daily,
+    /// At midnight, UTC, at the start of every Monday
+    Weekly,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+/// Settings for rotating the log files. When a log is rotated it is renamed
+/// with the suffix `.1`, after the suffixes of older logs have been increased
+/// by one, and the oldest logs beyond the number to keep are removed.
+pub struct Rotation {
+    /// Rotate a log once it reaches this many bytes
+    pub max_size: Option<u64>,
+    /// Rotate a log once this much time has passed since it was started
+    pub interval: Option<Interval>,
+    /// The number of old logs to keep
+    pub keep: usize,
+    /// Whether to compress old logs with gzip
+    #[serde(default)]
+    pub compress: bool,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+/// The syslog facility under which messages are logged
+pub enum Facility {
+    /// System daemons
+    #[default]
+    Daemon,
+    /// User level messages
+    User,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl Facility {
+    /// The numeric code of the facility
+    #[must_use]
+    pub fn code(self) -> u8 {
+        match self {
+            Self::User => 1,
+            Self::Daemon => 3,
+            Self::Local0 => 16,
+            Self::Local1 => 17,
+            Self::Local2 => 18,
+            Self::Local3 => 19,
+            Self::Local4 => 20,
+            Self::Local5 => 21,
+            Self::Local6 => 22,
+            Self::Local7 => 23,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+/// Where a log is written
+pub enum LogSink {
+    /// A file, which is kept open and may be rotated
+    File(PathBuf),
+    /// The local syslog daemon, which is sent RFC 5424 messages
+    Syslog {
+        /// The facility to log under
+        #[serde(default)]
+        facility: Facility,
+        /// The socket of the daemon, by default `/dev/log`
+        socket: Option<PathBuf>,
+    },
+    /// The systemd journal, which is sent structured entries using it's native
+    /// protocol
+    Journald {
+        /// The socket of the journal, by default
+        /// `/run/systemd/journal/socket`
+        socket: Option<PathBuf>,
+    },
+}
+
+impl LogSink {
+    /// The path of the log file, if this sink is a file
+    #[must_use]
+    pub fn file(&self) -> Option<&Path> {
+        match self {
+            Self::File(path) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LogSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Syslog { .. } => write!(f, "syslog"),
+            Self::Journald { .. } => write!(f, "journald"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+/// The severity of a log message, from most to least severe. Messages less
+/// severe than the configured level are not logged.
+pub enum Level {
+    /// Failures to handle a request, or of the server itself
+    Error,
+    /// Problems which do not prevent a request from being handled, such as
+    /// misbehaving clients and mistakes in the content being served
+    Warn,
+    /// Startup and shutdown of the server
+    #[default]
+    Info,
+    /// How each request is routed
+    Debug,
+    /// Fine grained details of how each request is handled
+    Trace,
+}
+
+impl Level {
+    const ALL: [Self; 5] = [
+        Self::Error,
+        Self::Warn,
+        Self::Info,
+        Self::Debug,
+        Self::Trace,
+    ];
+
+    /// Makes the level more verbose by `verbose` steps and less verbose by
+    /// `quiet` steps, stopping at `Error` and `Trace`
+    #[must_use]
+    pub fn adjust(self, verbose: usize, quiet: usize) -> Self {
+        let idx = (self as usize + verbose).saturating_sub(quiet);
+        Self::ALL[idx.min(Self::ALL.len() - 1)]
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warn => write!(f, "warn"),
+            Self::Info => write!(f, "info"),
+            Self::Debug => write!(f, "debug"),
+            Self::Trace => write!(f, "trace"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+/// How client ip addresses are anonymized in the logs and for CGI programs
+pub enum Anonymize {
+    /// Keeps only the first 24 bits of ipv4 addresses and the first 48 bits of
+    /// ipv6 addresses
+    Truncate,
+    /// Replaces each address with a keyed hash, the key being replaced daily
+    Hash,
+}
+
+#[derive(Deserialize)]
+/// A template for the lines of the access log, used in place of the log format
+/// for the records of requests
+pub enum AccessTemplate {
+    /// The Common Log Format, as read by most log analyzers
+    Common,
+    /// A line in which placeholders such as `{path}` are replaced by the
+    /// details of the request
+    Template(String),
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+/// The format of the lines written to the access and error logs
+pub enum LogFormat {
+    /// Plain text, with fields separated by spaces
+    #[default]
+    Text,
+    /// A JSON object on each line
+    Json,
+    /// Space separated `key=value` pairs
+    Logfmt,
+}
+
 #[derive(Deserialize)]
 /// Configuration variables for the server
 pub struct Config {
@@ -46,26 +268,119 @@ pub struct Config {
     /// The number of worker threads to launch
     pub threads: usize,
     /// Access log
-    pub access_log: Option<PathBuf>,
+    pub access_log: Option<LogSink>,
     /// Error log
-    pub error_log: Option<PathBuf>,
+    pub error_log: Option<LogSink>,
+    /// The least severe messages to log, made more or less verbose by the
+    /// `-v` and `-q` command line flags
+    #[serde(default)]
+    pub log_level: Level,
+    /// The format of log lines
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// An optional template for the records of requests in the access log
+    pub access_log_template: Option<AccessTemplate>,
+    /// Optional rotation of both logs
+    pub log_rotation: Option<Rotation>,
+    /// How client ip addresses are anonymized in the logs and for CGI
+    /// programs, if at all
+    pub anonymize_ips: Option<Anonymize>,
+    /// Ordered rules allowing or denying access to the entire server by ip
+    #[serde(default)]
+    pub access: Vec<AccessRule>,
     /// The Virtual Hosts to serve
     pub vhosts: HashMap<String, Server>,
+    /// The key of the Virtual Host serving requests for unknown hosts
+    pub default_vhost: Option<String>,
+    /// Maps each normalized host name onto the key of it's Virtual Host
+    #[serde(skip)]
+    hosts: HashMap<String, String>,
+    /// Maps the suffixes of wildcard host names, such as `.example.com`, onto
+    /// the key of their Virtual Host
+    #[serde(skip)]
+    wildcards: HashMap<String, String>,
+    /// An optional Gopher listener serving one of the virtual hosts
+    pub gopher: Option<Gopher>,
+    /// Limits applied to every client when it connects
+    pub rate_limit: Option<RateLimit>,
+    /// Maps file extensions onto mimetypes, taking precedence over the built
+    /// in defaults
+    #[serde(default)]
+    pub mimetypes: HashMap<String, String>,
+    /// The charset appended to the mimetype of text files, eg. `utf-8`
+    pub charset: Option<String>,
+    /// The language appended to the mimetype of gemtext files, eg. `en`
+    pub lang: Option<String>,
+    /// The number of seconds a CGI program may run before it is killed
+    pub cgi_timeout: Option<u64>,
+    /// An optional loopback address on which metrics are served in the
+    /// Prometheus text format
+    pub metrics: Option<Address>,
 }
 
 impl Default for Config {
     fn default() -> Self {
-        Self {
+        let mut cfg = Self {
             address: Address::default(),
             address1: None,
             user: String::from("agis"),
             group: String::from("agis"),
             threads: 4,
-            access_log: Some(PathBuf::from("/var/log/agis/access.log")),
-            error_log: Some(PathBuf::from("/var/log/agis/error.log")),
+            access_log: Some(LogSink::File(PathBuf::from("/var/log/agis/access.log"))),
+            error_log: Some(LogSink::File(PathBuf::from("/var/log/agis/error.log"))),
+            log_level: Level::default(),
+            log_format: LogFormat::default(),
+            access_log_template: None,
+            log_rotation: None,
+            anonymize_ips: None,
+            access: vec![],
             vhosts: HashMap::from([(String::from("example.com"), Server::default())]),
+            default_vhost: None,
+            hosts: HashMap::new(),
+            wildcards: HashMap::new(),
+            gopher: None,
+            rate_limit: None,
+            mimetypes: HashMap::new(),
+            charset: None,
+            lang: None,
+            cgi_timeout: None,
+            metrics: None,
+        };
+        // The default vhost can't conflict with itself
+        _ = cfg.index_vhosts();
+        cfg
+    }
+}
+
+/// Normalizes a host name for comparison by removing any port and trailing
+/// dot, converting it to lowercase and encoding internationalized domain names
+/// as punycode
+#[must_use]
+pub fn normalize_host(host: &str) -> String {
+    let host = if let Some(rest) = host.strip_prefix('[') {
+        // An ipv6 address, which may be followed by a port
+        rest.split_once(']').map_or(rest, |(h, _)| h)
+    } else {
+        match host.rsplit_once(':') {
+            Some((h, port)) if !h.contains(':') && port.bytes().all(|b| b.is_ascii_digit()) => h,
+            _ => host,
         }
+    };
+    let host = host.trim_end_matches('.');
+    if host.parse::<IpAddr>().is_ok() {
+        return host.to_ascii_lowercase();
     }
+    idna::domain_to_ascii(host).unwrap_or_else(|_| host.to_lowercase())
+}
+
+/// Wraps the bare paths which older configs give for `access_log` and
+/// `error_log`, as in `Some("/var/log/agis/access.log")`, as `File` sinks.
+/// Ron does not pass the name of a variant on to `deserialize_any`, so a
+/// `Deserialize` impl for `LogSink` could not accept both a bare string and
+/// every variant, and the config text is rewritten instead.
+fn wrap_bare_logs(raw: &str) -> Cow<'_, str> {
+    let re = Regex::new(r#"((?:access|error)_log\s*:\s*Some\(\s*)("(?:[^"\\]|\\.)*")"#).unwrap();
+    re.replace_all(raw, "${1}File($2)")
 }
 
 impl Config {
@@ -80,8 +395,12 @@ impl Config {
             .opt_str("c")
             .unwrap_or_else(|| "/etc/agis/config.ron".to_string());
         let raw = fs::read_to_string(cfg)?;
-        match ron::de::from_str(&raw) {
-            Ok(c) => Ok(c),
+        match ron::de::from_str::<Self>(&wrap_bare_logs(&raw)) {
+            Ok(mut c) => {
+                c.index_vhosts()?;
+                c.log_level = c.log_level.adjust(opts.opt_count("v"), opts.opt_count("q"));
+                Ok(c)
+            }
             Err(e) => {
                 let err = format!(
                     "Error encoding config:\n  code: {:?}\n  position:\n    line: {}\n    column: {}",
@@ -94,6 +413,69 @@ impl Config {
         }
     }
 
+    /// Builds the tables used to look up a Virtual Host by name, from the
+    /// keys, names and aliases of every vhost
+    /// # Errors
+    /// Returns an `io::Error` if a name is claimed by more than one vhost, or
+    /// if the default vhost is not the key of a vhost
+    fn index_vhosts(&mut self) -> Result<(), Error> {
+        self.hosts.clear();
+        self.wildcards.clear();
+        for (key, server) in &self.vhosts {
+            let names = [key, &server.name].into_iter().chain(&server.aliases);
+            for name in names {
+                let (table, name) = match name.strip_prefix("*.") {
+                    Some(domain) => (&mut self.wildcards, format!(".{}", normalize_host(domain))),
+                    None => (&mut self.hosts, normalize_host(name)),
+                };
+                if let Some(other) = table.get(&name).filter(|&other| other != key) {
+                    let err =
+                        format!("Host name {name} is claimed by both vhost {other} and {key}");
+                    return Err(Error::new(ErrorKind::InvalidData, err));
+                }
+                table.insert(name, key.clone());
+            }
+        }
+        if let Some(key) = &self.default_vhost {
+            if !self.vhosts.contains_key(key) {
+                let err = format!("The default vhost {key} is not the key of a vhost");
+                return Err(Error::new(ErrorKind::InvalidData, err));
+            }
+        }
+        if let Some(key) = self.gopher.as_ref().map(|g| &g.vhost) {
+            if !self.vhosts.contains_key(key) {
+                let err = format!("The Gopher vhost {key} is not the key of a vhost");
+                return Err(Error::new(ErrorKind::InvalidData, err));
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks up the Virtual Host which serves requests for `host`. Exact names
+    /// are preferred over wildcards, and the most specific wildcard wins. If no
+    /// vhost matches, the default vhost is used if one is configured.
+    #[must_use]
+    pub fn vhost(&self, host: &str) -> Option<&Server> {
+        self.named_vhost(host).or_else(|| {
+            self.default_vhost
+                .as_ref()
+                .and_then(|key| self.vhosts.get(key))
+        })
+    }
+
+    /// Looks up the Virtual Host which `host` names, either exactly or by a
+    /// wildcard, without falling back to the default vhost
+    #[must_use]
+    pub fn named_vhost(&self, host: &str) -> Option<&Server> {
+        let host = normalize_host(host);
+        let key = self.hosts.get(&host).or_else(|| {
+            // The suffixes of the host, from the longest to the shortest
+            host.match_indices('.')
+                .find_map(|(i, _)| self.wildcards.get(&host[i..]))
+        })?;
+        self.vhosts.get(key)
+    }
+
     /// Gets the `libc::passwd` for the user that the server will run as
     /// # Errors
     /// Returns an `io::Error` if unable to create a `CString`
@@ -120,3 +502,116 @@ impl Config {
         Ok(gid)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_host("Example.COM"), "example.com");
+        assert_eq!(normalize_host("example.com."), "example.com");
+        assert_eq!(normalize_host("example.com:300"), "example.com");
+        assert_eq!(normalize_host("bücher.example"), "xn--bcher-kva.example");
+        assert_eq!(normalize_host("127.0.0.1:300"), "127.0.0.1");
+        assert_eq!(normalize_host("[2001:DB8::1]:300"), "2001:db8::1");
+        assert_eq!(normalize_host("[2001:db8::1]"), "2001:db8::1");
+        // A bare ipv6 address must not lose it's last group as a port
+        assert_eq!(normalize_host("2001:db8::1"), "2001:db8::1");
+    }
+
+    /// A config with a vhost under each key, claiming the given aliases
+    fn config(vhosts: &[(&str, &[&str])]) -> Config {
+        let mut cfg = Config {
+            vhosts: HashMap::new(),
+            ..Config::default()
+        };
+        for (key, aliases) in vhosts {
+            let server = Server {
+                name: (*key).to_string(),
+                aliases: aliases.iter().map(ToString::to_string).collect(),
+                ..Server::default()
+            };
+            cfg.vhosts.insert((*key).to_string(), server);
+        }
+        cfg
+    }
+
+    /// The name of the vhost serving `host`
+    fn name<'a>(cfg: &'a Config, host: &str) -> Option<&'a str> {
+        cfg.vhost(host).map(|s| s.name.as_str())
+    }
+
+    #[test]
+    fn lookup() {
+        let mut cfg = config(&[
+            ("example.com", &["www.example.com", "*.example.com"]),
+            ("blog", &["*.blog.example.com"]),
+        ]);
+        cfg.index_vhosts().unwrap();
+        assert_eq!(name(&cfg, "WWW.Example.com:300"), Some("example.com"));
+        assert_eq!(name(&cfg, "a.example.com"), Some("example.com"));
+        // The most specific wildcard wins
+        assert_eq!(name(&cfg, "a.blog.example.com"), Some("blog"));
+        assert_eq!(name(&cfg, "example.org"), None);
+        cfg.default_vhost = Some(String::from("blog"));
+        assert_eq!(name(&cfg, "example.org"), Some("blog"));
+        assert!(cfg.named_vhost("example.org").is_none());
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut cfg = config(&[("a", &["shared.example"]), ("b", &["Shared.Example."])]);
+        assert!(cfg.index_vhosts().is_err());
+        let mut cfg = config(&[("a", &["*.example"]), ("b", &["*.example"])]);
+        assert!(cfg.index_vhosts().is_err());
+        let mut cfg = config(&[("a", &[])]);
+        cfg.default_vhost = Some(String::from("b"));
+        assert!(cfg.index_vhosts().is_err());
+    }
+
+    #[derive(Deserialize)]
+    struct Logs {
+        access_log: Option<LogSink>,
+        error_log: Option<LogSink>,
+    }
+
+    fn logs(raw: &str) -> Logs {
+        ron::de::from_str(&wrap_bare_logs(raw)).unwrap()
+    }
+
+    #[test]
+    fn bare_log_paths() {
+        let l =
+            logs(r#"(access_log: Some("/var/log/a.log"), error_log: Some( "/var/log/e\"1.log" ))"#);
+        assert_eq!(
+            l.access_log.unwrap().file(),
+            Some(Path::new("/var/log/a.log"))
+        );
+        assert_eq!(
+            l.error_log.unwrap().file(),
+            Some(Path::new("/var/log/e\"1.log"))
+        );
+        let l = logs(r#"(access_log: Some(File("/var/log/a.log")), error_log: None)"#);
+        assert_eq!(
+            l.access_log.unwrap().file(),
+            Some(Path::new("/var/log/a.log"))
+        );
+        assert!(l.error_log.is_none());
+        let l = logs(
+            r#"(access_log: Some(Syslog(facility: Local0, socket: None)),
+                error_log: Some(Journald(socket: Some("/run/journal"))))"#,
+        );
+        assert!(matches!(
+            l.access_log,
+            Some(LogSink::Syslog {
+                facility: Facility::Local0,
+                socket: None
+            })
+        ));
+        assert!(matches!(
+            l.error_log,
+            Some(LogSink::Journald { socket: Some(_) })
+        ));
+    }
+}
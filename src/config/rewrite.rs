@@ -0,0 +1,143 @@
+use {regex::Regex, serde::Deserialize, std::fmt};
+
+#[derive(Deserialize)]
+/// The pattern of a rewrite rule as written in the config file
+enum PatternSpec {
+    Regex(String),
+    Glob(String),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "PatternSpec")]
+/// A pattern matched against request paths. A pattern is given either as a
+/// regular expression, such as `Regex("^/posts/(\\d{4})/(.*)$")`, or as a glob,
+/// such as `Glob("/posts/*/*.gmi")`. A glob must match the entire path, with
+/// `*` matching any run of characters other than `/`, `**` matching any run of
+/// characters and `?` matching any one character other than `/`. Each wildcard
+/// in a glob is captured in turn as `$1`, `$2` and so on.
+pub struct Pattern(Regex);
+
+impl TryFrom<PatternSpec> for Pattern {
+    type Error = String;
+
+    fn try_from(spec: PatternSpec) -> Result<Self, Self::Error> {
+        let re = match spec {
+            PatternSpec::Regex(re) => re,
+            PatternSpec::Glob(glob) => {
+                let mut re = String::from("^");
+                let mut chars = glob.chars().peekable();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '*' if chars.peek() == Some(&'*') => {
+                            chars.next();
+                            re.push_str("(.*)");
+                        }
+                        '*' => re.push_str("([^/]*)"),
+                        '?' => re.push_str("([^/])"),
+                        c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+                    }
+                }
+                re.push('$');
+                re
+            }
+        };
+        Regex::new(&re)
+            .map(Self)
+            .map_err(|e| format!("Invalid rewrite pattern \"{re}\": {e}"))
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.as_str())
+    }
+}
+
+#[derive(Deserialize)]
+/// A rule rewriting request paths which match a pattern
+pub struct Rewrite {
+    /// The pattern which the request path must match
+    pub pattern: Pattern,
+    /// The new path, in which `$1`, `$2` and so on are replaced by the groups
+    /// captured by the pattern, and `$0` by the whole match
+    pub target: String,
+    /// Whether to send the client a redirect to the new path, rather than
+    /// serving it in place of the original path
+    #[serde(default)]
+    pub redirect: bool,
+    /// Whether a redirect is permanent
+    #[serde(default)]
+    pub permanent: bool,
+}
+
+impl Rewrite {
+    /// Finds the first rule whose pattern matches `path`, and returns it along
+    /// with the rewritten path
+    #[must_use]
+    pub fn first_match<'a>(rules: &'a [Self], path: &str) -> Option<(&'a Self, String)> {
+        rules.iter().find_map(|rule| {
+            let caps = rule.pattern.0.captures(path)?;
+            let mut target = String::new();
+            caps.expand(&rule.target, &mut target);
+            Some((rule, target))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(glob: &str) -> Pattern {
+        Pattern::try_from(PatternSpec::Glob(String::from(glob))).unwrap()
+    }
+
+    fn rule(pattern: Pattern, target: &str) -> Rewrite {
+        Rewrite {
+            pattern,
+            target: String::from(target),
+            redirect: false,
+            permanent: false,
+        }
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        let star = glob("/posts/*.gmi");
+        assert!(star.0.is_match("/posts/a.gmi"));
+        assert!(!star.0.is_match("/posts/2024/a.gmi"));
+        assert!(!star.0.is_match("/posts/a.gmi/b"));
+        let any = glob("/posts/**");
+        assert!(any.0.is_match("/posts/2024/a.gmi"));
+        let one = glob("/?.gmi");
+        assert!(one.0.is_match("/a.gmi"));
+        assert!(!one.0.is_match("/ab.gmi"));
+        assert!(!one.0.is_match("//.gmi"));
+    }
+
+    #[test]
+    fn glob_escapes() {
+        // Characters special to regular expressions are matched literally
+        let pattern = glob("/a.b+(c)");
+        assert!(pattern.0.is_match("/a.b+(c)"));
+        assert!(!pattern.0.is_match("/aXbb(c)"));
+    }
+
+    #[test]
+    fn captures() {
+        let rules = [
+            rule(glob("/old/*/**"), "/new/$2?from=$1"),
+            rule(glob("/**"), "/fallback/$1"),
+        ];
+        let (_, target) = Rewrite::first_match(&rules, "/old/2024/a/b.gmi").unwrap();
+        assert_eq!(target, "/new/a/b.gmi?from=2024");
+        let (_, target) = Rewrite::first_match(&rules, "/other").unwrap();
+        assert_eq!(target, "/fallback/other");
+        assert!(Rewrite::first_match(&rules[..1], "/other").is_none());
+    }
+
+    #[test]
+    fn invalid_regex() {
+        assert!(Pattern::try_from(PatternSpec::Regex(String::from("(unclosed"))).is_err());
+    }
+}
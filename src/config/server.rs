@@ -1,15 +1,118 @@
+use super::{AccessRule, LogSink, RateLimit, Rewrite};
+use crate::error::Kind;
 use serde::Deserialize;
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 #[derive(Deserialize)]
 /// A name-based virtual host
 pub struct Server {
     /// The domain name of this vserver
     pub name: String,
+    /// Other names which this vserver answers to. A name beginning with `*.`
+    /// matches every subdomain of the name which follows.
+    #[serde(default)]
+    pub aliases: Vec<String>,
     /// Where the files are to be served from
     pub root: PathBuf,
     /// Directory specific directives
     pub directories: HashMap<PathBuf, Directive>,
+    /// Ordered rules rewriting request paths, the first matching rule being
+    /// applied before any directives
+    #[serde(default)]
+    pub rewrites: Vec<Rewrite>,
+    /// The files to look for, in order, when a directory is requested
+    #[serde(default = "default_index")]
+    pub index: Vec<String>,
+    /// Whether to generate a listing for directories without an index file
+    #[serde(default = "default_autoindex")]
+    pub autoindex: bool,
+    /// How generated directory listings are presented
+    #[serde(default)]
+    pub listing: Listing,
+    /// Limits applied to every client making requests of this server, in
+    /// addition to the server wide limits
+    pub rate_limit: Option<RateLimit>,
+    /// Maps file extensions onto mimetypes, taking precedence over the server
+    /// wide table and the built in defaults
+    #[serde(default)]
+    pub mimetypes: HashMap<String, String>,
+    /// The charset of text files served by this server, overriding the server
+    /// wide setting
+    pub charset: Option<String>,
+    /// The language of gemtext files served by this server, overriding the
+    /// server wide setting
+    pub lang: Option<String>,
+    /// Maps kinds of error onto the response sent to the client in their place
+    #[serde(default)]
+    pub errors: HashMap<Kind, ErrorPage>,
+    /// Where requests made of this server are logged, in place of the server
+    /// wide access log
+    pub access_log: Option<LogSink>,
+    /// Where errors in requests made of this server are logged, in place of
+    /// the server wide error log
+    pub error_log: Option<LogSink>,
+}
+
+#[derive(Deserialize)]
+/// What is sent to the client in place of an error
+pub enum ErrorPage {
+    /// Replaces the message sent along with the error status
+    Message(String),
+    /// Redirects the client to another path
+    Redirect(String),
+    /// Serves a gemtext file, given relative to the server root
+    Page(PathBuf),
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+/// The order of entries in a directory listing. Directories always come first.
+pub enum SortBy {
+    /// Alphabetically by name
+    #[default]
+    Name,
+    /// Most recently modified first
+    Modified,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+/// How generated directory listings are presented
+pub struct Listing {
+    /// The order of the entries
+    pub sort: SortBy,
+    /// Whether to list files and directories whose names begin with a dot
+    pub show_hidden: bool,
+    /// Whether to show the size and modification time of each entry
+    pub details: bool,
+    /// A gemtext file which, if present in the directory, is shown above the
+    /// listing in place of the default title
+    pub header: String,
+    /// A gemtext file which, if present in the directory, is shown below the
+    /// listing
+    pub footer: String,
+}
+
+impl Default for Listing {
+    fn default() -> Self {
+        Self {
+            sort: SortBy::Name,
+            show_hidden: false,
+            details: true,
+            header: String::from(".header.gmi"),
+            footer: String::from(".footer.gmi"),
+        }
+    }
+}
+
+fn default_index() -> Vec<String> {
+    vec![String::from("index.gmi")]
+}
+
+fn default_autoindex() -> bool {
+    true
 }
 
 #[derive(Deserialize)]
@@ -17,24 +120,117 @@ pub struct Server {
 pub enum Directive {
     /// Denies access to this path
     Allow(bool),
+    /// Allows or denies access to this path by client ip. The rules are checked
+    /// in order and the first one matching the client decides.
+    Access(Vec<AccessRule>),
     /// Causes requests for this path to be served from a different path
     Alias(String),
-    /// Causes to server to send a redirect code back to the client
-    Redirect(PathBuf),
+    /// Causes to server to send a redirect code back to the client. The part
+    /// of the request path following the directive's path, and any query
+    /// string, are carried over to the target.
+    Redirect(String),
+    /// As Redirect, but tells the client that the resource has moved for good
+    PermanentRedirect(String),
     /// Files under this directory will be processed by the given interpreter
     Interpreter(String),
     /// Paths under this directory are Common Gateway Interface programs
     Cgi,
     /// Paths under this directory will run <script>
     ScriptAlias(PathBuf),
+    /// Overrides the index files and directory listing setting of the server
+    /// for directories under this path
+    Index {
+        /// The files to look for, in order, when a directory is requested
+        files: Vec<String>,
+        /// Whether to generate a listing for directories without an index file
+        autoindex: bool,
+    },
+    /// Overrides the language and charset of the server for files under this
+    /// path
+    Meta {
+        /// The language of gemtext files
+        lang: Option<String>,
+        /// The charset of text files
+        charset: Option<String>,
+    },
+    /// Input prompts (`=:` lines) in gemtext files under this directory will
+    /// be checked to point at a path which accepts input
+    Prompt,
+    /// Resources under this path have been permanently removed
+    Gone,
+    /// Request bodies sent to paths under this directory will be stored as
+    /// files under <dir>
+    Upload {
+        /// The directory to store uploads in, relative to the server root
+        dir: PathBuf,
+        /// The maximum size of an upload in bytes
+        max_size: usize,
+        /// The mimetypes which may be uploaded. If empty, any type is allowed
+        allowed_mimetypes: Vec<String>,
+        /// Whether an upload may replace an existing file
+        overwrite: bool,
+        /// If set, every write must carry a token signed with this secret,
+        /// and files may also be deleted
+        secret: Option<String>,
+    },
+}
+
+impl Directive {
+    /// The name of this kind of directive
+    #[must_use]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Allow(_) => "Allow",
+            Self::Access(_) => "Access",
+            Self::Alias(_) => "Alias",
+            Self::Redirect(_) => "Redirect",
+            Self::PermanentRedirect(_) => "PermanentRedirect",
+            Self::Interpreter(_) => "Interpreter",
+            Self::Cgi => "Cgi",
+            Self::ScriptAlias(_) => "ScriptAlias",
+            Self::Index { .. } => "Index",
+            Self::Meta { .. } => "Meta",
+            Self::Prompt => "Prompt",
+            Self::Gone => "Gone",
+            Self::Upload { .. } => "Upload",
+        }
+    }
 }
 
 impl Default for Server {
     fn default() -> Self {
         Self {
             name: String::from("example.com"),
+            aliases: vec![],
             root: PathBuf::from("/srv/spartan"),
             directories: HashMap::from([(PathBuf::from("/"), Directive::Allow(true))]),
+            rewrites: vec![],
+            index: default_index(),
+            autoindex: default_autoindex(),
+            listing: Listing::default(),
+            rate_limit: None,
+            mimetypes: HashMap::new(),
+            charset: None,
+            lang: None,
+            errors: HashMap::new(),
+            access_log: None,
+            error_log: None,
         }
     }
 }
+
+impl Server {
+    /// Whether requests for this path are able to accept input in the request
+    /// body, which is the case for any path handled by a CGI program or
+    /// accepting uploads
+    #[must_use]
+    pub fn accepts_input(&self, path: &Path) -> bool {
+        self.directories.iter().any(|(dir, directive)| {
+            path.starts_with(dir)
+                && matches!(
+                    directive,
+                    Directive::Cgi | Directive::ScriptAlias(_) | Directive::Upload { .. }
+                )
+        })
+    }
+}
@@ -1,5 +1,76 @@
 #![allow(clippy::module_name_repetitions)]
-use std::{error::Error, fmt, io, string::FromUtf8Error};
+use {
+    serde::Deserialize,
+    std::{error::Error, fmt, io, string::FromUtf8Error},
+};
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "String")]
+/// The kinds of request and server error, by whose names a virtual host may
+/// configure the response sent in place of an error. Each is named after the
+/// `RequestError` or `ServerError` variant it stands for.
+pub enum Kind {
+    MissingSeparator,
+    MissingField,
+    ExtraField,
+    InvalidContentLength,
+    InvalidUtf8,
+    ReadError,
+    BodyNotAccepted,
+    TooLarge,
+    MimetypeNotAllowed,
+    InvalidPath,
+    AlreadyExists,
+    InvalidToken,
+    NotFound,
+    CgiError,
+    Unauthorized,
+    IoError,
+    SlowDown,
+    Gone,
+    Timeout,
+    Busy,
+    RewriteLoop,
+}
+
+impl Kind {
+    /// Every kind of error, along with it's name
+    const ALL: [(Self, &'static str); 21] = [
+        (Self::MissingSeparator, "MissingSeparator"),
+        (Self::MissingField, "MissingField"),
+        (Self::ExtraField, "ExtraField"),
+        (Self::InvalidContentLength, "InvalidContentLength"),
+        (Self::InvalidUtf8, "InvalidUtf8"),
+        (Self::ReadError, "ReadError"),
+        (Self::BodyNotAccepted, "BodyNotAccepted"),
+        (Self::TooLarge, "TooLarge"),
+        (Self::MimetypeNotAllowed, "MimetypeNotAllowed"),
+        (Self::InvalidPath, "InvalidPath"),
+        (Self::AlreadyExists, "AlreadyExists"),
+        (Self::InvalidToken, "InvalidToken"),
+        (Self::NotFound, "NotFound"),
+        (Self::CgiError, "CgiError"),
+        (Self::Unauthorized, "Unauthorized"),
+        (Self::IoError, "IoError"),
+        (Self::SlowDown, "SlowDown"),
+        (Self::Gone, "Gone"),
+        (Self::Timeout, "Timeout"),
+        (Self::Busy, "Busy"),
+        (Self::RewriteLoop, "RewriteLoop"),
+    ];
+}
+
+impl TryFrom<String> for Kind {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        Self::ALL
+            .iter()
+            .find(|(_, n)| *n == name)
+            .map(|(kind, _)| *kind)
+            .ok_or_else(|| format!("unknown kind of error `{name}`"))
+    }
+}
 
 #[derive(Debug)]
 /// Errors which might occur while parsing a request
@@ -16,6 +87,18 @@ pub enum RequestError {
     InvalidUtf8,
     /// There was an error reading the request
     ReadError(std::io::Error),
+    /// The request carried a body, but the requested path does not accept input
+    BodyNotAccepted,
+    /// The request body is larger than allowed for this path
+    TooLarge,
+    /// The mimetype of the request body is not allowed for this path
+    MimetypeNotAllowed(String),
+    /// The requested path is not a valid place to store an upload
+    InvalidPath,
+    /// An upload would replace an existing resource
+    AlreadyExists,
+    /// A write was not accompanied by a valid token
+    InvalidToken,
 }
 
 impl fmt::Display for RequestError {
@@ -27,6 +110,54 @@ impl fmt::Display for RequestError {
             Self::InvalidContentLength => write!(f, "Invalid content length"),
             Self::InvalidUtf8 => write!(f, "Utf8 error"),
             Self::ReadError(e) => write!(f, "Read error: {e}"),
+            Self::BodyNotAccepted => write!(f, "Input not accepted"),
+            Self::TooLarge => write!(f, "Request body too large"),
+            Self::MimetypeNotAllowed(m) => write!(f, "Mimetype not allowed: {m}"),
+            Self::InvalidPath => write!(f, "Invalid upload path"),
+            Self::AlreadyExists => write!(f, "Resource already exists"),
+            Self::InvalidToken => write!(f, "Invalid or missing token"),
+        }
+    }
+}
+
+impl RequestError {
+    /// The kind of this error, by which a virtual host may configure the
+    /// response sent in it's place
+    #[must_use]
+    pub fn kind(&self) -> Kind {
+        match self {
+            Self::MissingSeparator => Kind::MissingSeparator,
+            Self::MissingField => Kind::MissingField,
+            Self::ExtraField => Kind::ExtraField,
+            Self::InvalidContentLength => Kind::InvalidContentLength,
+            Self::InvalidUtf8 => Kind::InvalidUtf8,
+            Self::ReadError(_) => Kind::ReadError,
+            Self::BodyNotAccepted => Kind::BodyNotAccepted,
+            Self::TooLarge => Kind::TooLarge,
+            Self::MimetypeNotAllowed(_) => Kind::MimetypeNotAllowed,
+            Self::InvalidPath => Kind::InvalidPath,
+            Self::AlreadyExists => Kind::AlreadyExists,
+            Self::InvalidToken => Kind::InvalidToken,
+        }
+    }
+
+    /// The message sent to the client, which unlike the `Display` text never
+    /// includes the details of the error
+    #[must_use]
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::MissingSeparator => "Missing separator",
+            Self::MissingField => "Missing field",
+            Self::ExtraField => "Extra field",
+            Self::InvalidContentLength => "Invalid content length",
+            Self::InvalidUtf8 => "Utf8 error",
+            Self::ReadError(_) => "Bad request",
+            Self::BodyNotAccepted => "Input not accepted",
+            Self::TooLarge => "Request body too large",
+            Self::MimetypeNotAllowed(_) => "Mimetype not allowed",
+            Self::InvalidPath => "Invalid upload path",
+            Self::AlreadyExists => "Resource already exists",
+            Self::InvalidToken => "Invalid or missing token",
         }
     }
 }
@@ -63,6 +194,16 @@ pub enum ServerError {
     Unauthorized,
     /// The server encountered an io error
     IoError(std::io::Error),
+    /// The client has exceeded it's rate limit
+    SlowDown,
+    /// The requested resource has been permanently removed
+    Gone,
+    /// Processing the request took longer than allowed
+    Timeout,
+    /// The server lacks the resources to process the request at this time
+    Busy,
+    /// The request was rewritten or aliased too many times
+    RewriteLoop,
 }
 
 impl fmt::Display for ServerError {
@@ -72,6 +213,63 @@ impl fmt::Display for ServerError {
             Self::CgiError => write!(f, "Script failed"),
             Self::Unauthorized => write!(f, "Not authorized"),
             Self::IoError(e) => write!(f, "Io error: {e}"),
+            Self::SlowDown => write!(f, "Slow down"),
+            Self::Gone => write!(f, "Resource gone"),
+            Self::Timeout => write!(f, "Timed out"),
+            Self::Busy => write!(f, "Server busy"),
+            Self::RewriteLoop => write!(f, "Too many rewrites"),
+        }
+    }
+}
+
+impl ServerError {
+    /// The kind of this error, by which a virtual host may configure the
+    /// response sent in it's place
+    #[must_use]
+    pub fn kind(&self) -> Kind {
+        match self {
+            Self::NotFound => Kind::NotFound,
+            Self::CgiError => Kind::CgiError,
+            Self::Unauthorized => Kind::Unauthorized,
+            Self::IoError(_) => Kind::IoError,
+            Self::SlowDown => Kind::SlowDown,
+            Self::Gone => Kind::Gone,
+            Self::Timeout => Kind::Timeout,
+            Self::Busy => Kind::Busy,
+            Self::RewriteLoop => Kind::RewriteLoop,
+        }
+    }
+
+    /// The message sent to the client, which unlike the `Display` text never
+    /// includes the details of the error
+    #[must_use]
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::NotFound => "Resource not found",
+            Self::CgiError => "Script failed",
+            Self::Unauthorized => "Not authorized",
+            Self::IoError(_) | Self::RewriteLoop => "Internal server error",
+            Self::SlowDown => "Slow down",
+            Self::Gone => "Resource gone",
+            Self::Timeout => "Timed out",
+            Self::Busy => "Server busy",
+        }
+    }
+
+    /// The Spartan status sent along with this error. Errors which the client
+    /// could avoid by requesting something else are sent as client errors
+    /// (status 4), while those arising from the state of the server are sent
+    /// as server errors (status 5).
+    #[must_use]
+    pub fn status(&self) -> u8 {
+        match self {
+            Self::NotFound | Self::Unauthorized | Self::Gone => 4,
+            Self::CgiError
+            | Self::IoError(_)
+            | Self::SlowDown
+            | Self::Timeout
+            | Self::Busy
+            | Self::RewriteLoop => 5,
         }
     }
 }
@@ -87,6 +285,12 @@ impl Error for ServerError {
 
 impl From<io::Error> for ServerError {
     fn from(error: io::Error) -> Self {
-        Self::IoError(error)
+        match error.kind() {
+            io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => Self::NotFound,
+            io::ErrorKind::PermissionDenied => Self::Unauthorized,
+            io::ErrorKind::TimedOut => Self::Timeout,
+            io::ErrorKind::ResourceBusy | io::ErrorKind::WouldBlock => Self::Busy,
+            _ => Self::IoError(error),
+        }
     }
 }
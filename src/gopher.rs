@@ -0,0 +1,256 @@
+//! Serves the document tree of a single virtual host over the Gopher protocol.
+//! Selectors are mapped onto the same paths as Spartan requests, so directives
+//! such as `Cgi`, `ScriptAlias` and `Alias` behave identically for both
+//! protocols. Gemtext documents, including generated directory listings, are
+//! converted into gophermaps on the fly, while any other content is sent as is.
+//!
+//! Spartan input prompts (`=:` lines) become Gopher search items. The search
+//! string sent by the client is passed on as the request body, so CGI programs
+//! receive it in exactly the same way as input from a Spartan client.
+
+use {
+    crate::{
+        config::{Gopher, Level},
+        error::{RequestError, ServerError},
+        limit,
+        log::{self, Log, LogLevel, Record},
+        metrics,
+        request::{self, Request},
+        response::Response,
+        CONFIG,
+    },
+    std::{
+        ffi::OsStr,
+        io::{self, BufRead, BufReader, BufWriter, Write},
+        net::TcpStream,
+        path::Path,
+    },
+};
+
+/// The number of redirects which will be followed internally before giving up,
+/// as Gopher has no way to send a redirect to the client
+const MAX_REDIRECTS: usize = 5;
+
+/// Reads a selector line from the stream and turns it into a `Request` for
+/// the virtual host served over Gopher
+fn read_request(stream: &TcpStream, gopher: &Gopher) -> Result<Request, RequestError> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let Some(line) = line
+        .strip_suffix("\r\n")
+        .or_else(|| line.strip_suffix('\n'))
+    else {
+        return Err(RequestError::MissingSeparator);
+    };
+    let (selector, search) = match line.split_once('\t') {
+        Some((s, q)) => (s, Some(q.as_bytes().to_vec())),
+        None => (line, None),
+    };
+    let selector = urlencoding::decode(selector)?;
+    let (mut path, query) = if let Some((p, q)) = selector.split_once('?') {
+        (p.to_string(), Some(q.to_string()))
+    } else {
+        (selector.to_string(), None)
+    };
+    if !path.starts_with('/') {
+        path.insert(0, '/');
+    }
+    let client_ip = stream.peer_addr()?.ip();
+    Ok(Request {
+        host: gopher.vhost.clone(),
+        path,
+        query,
+        client_ip,
+        length: search.as_ref().map_or(0, Vec::len),
+        content: search,
+    })
+}
+
+/// Formulates a response, following any redirects internally. Returns the
+/// path which was finally served along with the `Response`, so that relative
+/// links can be resolved correctly.
+fn fetch(mut request: Request) -> (String, Response) {
+    let mut redirects = 0;
+    loop {
+        let path = request.path.clone();
+        match Response::from(request.clone()) {
+            // Redirects to another host can't be followed over Gopher
+            Response::Redirect { target, .. }
+                if redirects < MAX_REDIRECTS && !target.contains("://") =>
+            {
+                request.path = request::resolve(&path, &target);
+                redirects += 1;
+            }
+            response => return (path, response),
+        }
+    }
+}
+
+/// Guesses the Gopher item type of a local path
+fn item_type(path: &str, root: &Path) -> char {
+    let path = path.split_once('?').map_or(path, |(p, _)| p);
+    if path.ends_with('/') || root.join(path.trim_start_matches('/')).is_dir() {
+        return '1';
+    }
+    match Path::new(path).extension().and_then(OsStr::to_str) {
+        // Gemtext is converted into a gophermap when it is served, as is the
+        // output of extensionless CGI programs
+        None | Some("gmi" | "gemini") => '1',
+        Some("txt" | "md") => '0',
+        Some("gif") => 'g',
+        Some("png" | "jpg" | "jpeg" | "webp" | "bmp") => 'I',
+        Some("html" | "htm") => 'h',
+        Some(_) => '9',
+    }
+}
+
+/// Formats a single gophermap line
+fn item(kind: char, display: &str, selector: &str, host: &str, port: &str) -> String {
+    let display = display.replace('\t', "    ");
+    format!("{kind}{display}\t{selector}\t{host}\t{port}\r\n")
+}
+
+/// Formats an informational gophermap line
+fn info(text: &str) -> String {
+    item('i', text, "", "null.host", "1")
+}
+
+/// Converts the body of a gemtext link or prompt line into a gophermap item
+fn link(line: &str, base: &str, gopher: &Gopher, root: &Path, prompt: bool) -> String {
+    let line = line.trim();
+    let (url, label) = match line.split_once(char::is_whitespace) {
+        Some((u, l)) => (u, l.trim()),
+        None => (line, line),
+    };
+    if url.is_empty() {
+        return info(line);
+    }
+    let (host, port) = (gopher.hostname.as_str(), gopher.address.port.as_str());
+    if let Some(rest) = url.strip_prefix("gopher://") {
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let (host, port) = authority.rsplit_once(':').unwrap_or((authority, "70"));
+        let mut chars = path.chars();
+        let kind = chars.next().unwrap_or('1');
+        item(kind, label, chars.as_str(), host, port)
+    } else if url.contains("://") || url.starts_with("mailto:") {
+        item('h', label, &format!("URL:{url}"), host, port)
+    } else {
+        let path = request::resolve(base, &urlencoding::decode(url).unwrap_or_else(|_| url.into()));
+        let kind = if prompt { '7' } else { item_type(&path, root) };
+        item(kind, label, &path, host, port)
+    }
+}
+
+/// Converts a gemtext document into a gophermap
+fn gophermap(gemtext: &str, base: &str, gopher: &Gopher, root: &Path) -> String {
+    let mut map = String::new();
+    let mut preformatted = false;
+    for line in gemtext.lines() {
+        if line.starts_with("```") {
+            preformatted = !preformatted;
+            continue;
+        }
+        if !preformatted {
+            if let Some(l) = line.strip_prefix("=>") {
+                map.push_str(&link(l, base, gopher, root, false));
+                continue;
+            }
+            if let Some(l) = line.strip_prefix("=:") {
+                map.push_str(&link(l, base, gopher, root, true));
+                continue;
+            }
+        }
+        map.push_str(&info(line));
+    }
+    map.push_str(".\r\n");
+    map
+}
+
+/// Formats an error as a Gopher error item
+fn error(msg: &str) -> Vec<u8> {
+    let mut buf = item('3', msg, "", "null.host", "1");
+    buf.push_str(".\r\n");
+    buf.into_bytes()
+}
+
+/// Attempts to parse a Gopher selector from the stream and to formulate a
+/// response based upon that input.
+/// # Errors
+/// Returns an `io::Error` if:
+/// * Unable to log an error
+/// * Unable to write to the `TcpStream` successfully
+pub fn handle_connection(mut stream: TcpStream) -> Result<(), io::Error> {
+    let Some(gopher) = CONFIG.gopher.as_ref() else {
+        return Ok(());
+    };
+    let mut record = Record::start(stream.peer_addr().ok().map(|a| a.ip()));
+    let request = read_request(&stream, gopher);
+    record.parsed();
+    let (request, (path, response)) = match request {
+        Ok(request) => {
+            let server = CONFIG.named_vhost(&request.host);
+            log::set_vhost(server);
+            record.request(&request, server);
+            (
+                request.to_string(),
+                match limit::admit_vhost(&request) {
+                    Ok(_guard) => fetch(request),
+                    Err(e) => (request.path.clone(), e.into()),
+                },
+            )
+        }
+        Err(e) => (
+            String::from("Malformed request"),
+            (String::from("/"), e.into()),
+        ),
+    };
+    if !matches!(response, Response::Success { .. }) {
+        let level = if response.status() == 5 {
+            Level::Error
+        } else {
+            Level::Warn
+        };
+        format!("{request} {response}").log_at(level)?;
+    }
+    record.response(&response);
+    let body = match response {
+        Response::Success { mimetype, body } => {
+            if mimetype.starts_with("text/gemini") {
+                let root = CONFIG
+                    .vhost(&gopher.vhost)
+                    .map(|s| s.root.clone())
+                    .unwrap_or_default();
+                gophermap(&String::from_utf8_lossy(&body), &path, gopher, &root).into_bytes()
+            } else {
+                body
+            }
+        }
+        Response::Redirect { target, .. } => {
+            if target.contains("://") {
+                error(&format!("Moved to {target}"))
+            } else {
+                error("Too many redirects")
+            }
+        }
+        Response::ClientError(e) => error(e.message()),
+        Response::ServerError(e) => error(e.message()),
+        Response::CustomError { message, .. } => error(&message),
+    };
+    let mut writer = BufWriter::new(&mut stream);
+    let result = writer.write_all(&body).and_then(|()| writer.flush());
+    record.finish(body.len(), &result);
+    metrics::observe(&record);
+    record.log()?;
+    log::set_vhost(None);
+    result
+}
+
+/// Sends an error item to a client whose connection is refused before a
+/// selector has been read
+/// # Errors
+/// Returns an `io::Error` if unable to write to the `TcpStream`
+pub fn refuse(stream: TcpStream, error: ServerError) -> Result<(), io::Error> {
+    let body = self::error(error.message());
+    crate::refuse_with(stream, error, &body)
+}
@@ -5,33 +5,52 @@
 pub mod config;
 /// Possible errors
 pub mod error;
+/// Serves a virtual host over the Gopher protocol
+pub mod gopher;
+/// Per client rate limiting
+pub mod limit;
 /// Log access and errors
 pub mod log;
+/// Serves metrics in the Prometheus text format
+pub mod metrics;
 /// Parses requests
 pub mod request;
 /// Prepares a resonse
 pub mod response;
+/// Keyed hashes
+pub mod sign;
 /// Creates and manages worker threads
 pub mod threadpool;
 
 use {
+    config::{Level, LogSink},
+    error::{RequestError, ServerError},
     getopts::{Fail, Matches, Options},
-    log::{Log, LogError},
+    log::{Log, LogLevel, Record},
     once_cell::sync::Lazy,
     response::Response,
     std::{
         env,
         ffi::CString,
         fs::{self, File},
-        io::{self, BufWriter, Write},
-        net::TcpStream,
-        os::unix::prelude::OsStrExt,
+        io::{self, BufWriter, Read, Write},
+        net::{Shutdown, TcpStream},
+        os::unix::{fs::MetadataExt, prelude::OsStrExt},
+        path::Path,
         process,
+        time::Duration,
     },
 };
 
 pub use {config::Config, request::Request, threadpool::ThreadPool};
 
+/// How long a refused client is given to take it's error
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The most of an unread request body which is discarded once the request has
+/// been refused
+const DRAIN_LIMIT: u64 = 64 * 1024;
+
 pub static CONFIG: Lazy<Config> = Lazy::new(|| match Config::load() {
     Ok(c) => c,
     Err(e) => {
@@ -62,52 +81,100 @@ pub unsafe fn privdrop(user: *mut libc::passwd, group: *mut libc::group) -> io::
     Ok(())
 }
 
-/// Initializes the access and error logs if they don't exist
+/// Whether the server user may create files in `dir`, going by it's mode
+fn writable(dir: &Path, user: libc::uid_t, group: libc::gid_t) -> io::Result<bool> {
+    let meta = fs::metadata(dir)?;
+    let mode = meta.mode();
+    Ok(if meta.uid() == user {
+        mode & 0o300 == 0o300
+    } else if meta.gid() == group {
+        mode & 0o030 == 0o030
+    } else {
+        mode & 0o003 == 0o003
+    })
+}
+
+/// Creates a log file, and the directory containing it, if they don't exist,
+/// giving them to the server user
+unsafe fn init_log(
+    log: &Path,
+    name: &str,
+    user: libc::uid_t,
+    group: libc::gid_t,
+) -> Result<(), io::Error> {
+    // These are printed rather than logged, as logging them could open the
+    // very file being created before it has been given to the server user
+    let note = |msg: &str| {
+        if log::enabled(Level::Debug) {
+            println!("{msg}");
+        }
+    };
+    if let Some(parent) = log.parent() {
+        if !parent.exists() {
+            note("Creating log directory");
+            fs::create_dir_all(parent)?;
+            // The server must be able to create files in the directory
+            // in order to rotate it's logs
+            let dirstr = CString::new(parent.as_os_str().as_bytes())?;
+            _ = libc::chown(dirstr.as_ptr(), user, group);
+        } else if CONFIG.log_rotation.is_some() && !writable(parent, user, group)? {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Unable to rotate logs, as the server user can't write to {}",
+                    parent.display()
+                ),
+            ));
+        }
+    }
+    if !log.exists() {
+        note(&format!("Creating {name}"));
+        {
+            File::create(log)?;
+        }
+        let logstr = CString::new(log.as_os_str().as_bytes())?;
+        note(&format!("Setting {name} permissions"));
+        _ = libc::chown(logstr.as_ptr(), user, group);
+    }
+    Ok(())
+}
+
+/// Initializes the access and error log files, both server wide and for each
+/// virtual host, if they don't exist
 /// # Safety
 /// This function uses a number of unsafe libc interfaces. It is only called at
-/// startup time, and the unsafe code only runs if either log is missing.
+/// startup time, and the unsafe code only runs if a log is missing.
 /// # Errors
 /// Returns an error if
 /// * Unable to create logging directory
 /// * Unable to create access or error log files
 pub unsafe fn init_logs(user: libc::uid_t, group: libc::gid_t) -> Result<(), io::Error> {
-    if let Some(log) = CONFIG.access_log.as_ref() {
-        if let Some(parent) = log.parent() {
-            if !parent.exists() {
-                println!("Creating log directory");
-                fs::create_dir_all(parent)?;
-            }
-        }
-        if !log.exists() {
-            println!("Creating access log");
-            {
-                File::create(log)?;
-            }
-            let logstr = CString::new(log.clone().as_os_str().as_bytes())?;
-            println!("Setting access log permissions");
-            _ = libc::chown(logstr.as_ptr(), user, group);
-        }
+    if let Some(log) = CONFIG.access_log.as_ref().and_then(LogSink::file) {
+        init_log(log, "access log", user, group)?;
     }
-    if let Some(log) = CONFIG.error_log.as_ref() {
-        if let Some(parent) = log.parent() {
-            if !parent.exists() {
-                println!("Creating log directory");
-                fs::create_dir_all(parent)?;
-            }
+    if let Some(log) = CONFIG.error_log.as_ref().and_then(LogSink::file) {
+        init_log(log, "error log", user, group)?;
+    }
+    for server in CONFIG.vhosts.values() {
+        if let Some(log) = server.access_log.as_ref().and_then(LogSink::file) {
+            init_log(log, &format!("access log for {}", server.name), user, group)?;
         }
-        if !log.exists() {
-            println!("Creating error log");
-            {
-                File::create(log)?;
-            }
-            let logstr = CString::new(log.clone().as_os_str().as_bytes())?;
-            println!("Setting error log permissions");
-            _ = libc::chown(logstr.as_ptr(), user, group);
+        if let Some(log) = server.error_log.as_ref().and_then(LogSink::file) {
+            init_log(log, &format!("error log for {}", server.name), user, group)?;
         }
     }
     Ok(())
 }
 
+/// Discards the unread body of a refused request, up to a bound, after the
+/// response has been sent. Closing a connection with data left unread resets
+/// it, which could keep the response from ever reaching the client.
+fn drain(stream: &TcpStream) {
+    _ = stream.shutdown(Shutdown::Write);
+    _ = stream.set_read_timeout(Some(REFUSE_TIMEOUT));
+    _ = io::copy(&mut stream.take(DRAIN_LIMIT), &mut io::sink());
+}
+
 /// Attempts to parse a `Request` from the stream and to formulate
 /// a `Response` based upon that input.
 /// # Errors
@@ -115,28 +182,99 @@ pub unsafe fn init_logs(user: libc::uid_t, group: libc::gid_t) -> Result<(), io:
 /// * Unable to log an error
 /// * Unable to write to the `TcpStream` successfully
 pub fn handle_connection(mut stream: TcpStream) -> Result<(), io::Error> {
-    let (request, response) = match Request::try_from(&stream) {
-        Ok(request) => (request.to_string(), Response::from(request)),
-        Err(e) => (String::from("Malformed request"), e.into()),
-    };
-    let msg = response.to_string();
-    match response {
-        Response::Success {
-            mimetype: _,
-            body: _,
-        }
-        | Response::Redirect(_) => {
-            request.log()?;
-            msg.log()?;
-        }
-        Response::ClientError(_) | Response::ServerError(_) => {
-            request.log_err()?;
-            msg.log_err()?;
+    let mut record = Record::start(stream.peer_addr().ok().map(|a| a.ip()));
+    let request = Request::try_from(&stream);
+    record.parsed();
+    // A body which was refused before being read is still waiting
+    let unread = matches!(
+        request,
+        Err(RequestError::TooLarge | RequestError::BodyNotAccepted)
+    );
+    let (request, response, server) = match request {
+        Ok(request) => {
+            let server = CONFIG.vhost(&request.host);
+            // A default vhost serves unknown hosts, but doesn't log them
+            let named = CONFIG.named_vhost(&request.host);
+            log::set_vhost(named);
+            record.request(&request, named);
+            (
+                request.to_string(),
+                match limit::admit_vhost(&request) {
+                    Ok(_guard) => Response::from(request),
+                    Err(e) => e.into(),
+                },
+                server,
+            )
         }
+        Err(e) => (String::from("Malformed request"), e.into(), None),
+    };
+    if matches!(
+        response,
+        Response::ClientError(_) | Response::ServerError(_)
+    ) {
+        // Requests which could not be satisfied are only warnings, unless the
+        // server itself failed
+        let level = if response.status() == 5 {
+            Level::Error
+        } else {
+            Level::Warn
+        };
+        format!("{request} {response}").log_at(level)?;
     }
+    // The details of an error have been logged, so the client may be sent a
+    // configured response in it's place
+    let response = match server {
+        Some(server) => response.error_page(server),
+        None => response,
+    };
+    record.response(&response);
+    let buf = Vec::from(response);
     let mut writer = BufWriter::new(&mut stream);
-    writer.write_all(&Vec::from(response))?;
-    Ok(())
+    let result = writer.write_all(&buf).and_then(|()| writer.flush());
+    record.finish(buf.len(), &result);
+    if unread {
+        drain(writer.get_ref());
+    }
+    metrics::observe(&record);
+    record.log()?;
+    log::set_vhost(None);
+    result
+}
+
+/// Sends `body` to a client whose connection is refused with `error` before a
+/// request has been read. The client is given a short time to take it, so that
+/// a slow client can't hold up the thread sending refusals.
+/// # Errors
+/// Returns an `io::Error` if unable to write to the `TcpStream`
+pub fn refuse_with(
+    mut stream: TcpStream,
+    error: ServerError,
+    body: &[u8],
+) -> Result<(), io::Error> {
+    let mut record = Record::start(stream.peer_addr().ok().map(|a| a.ip()));
+    stream.set_write_timeout(Some(REFUSE_TIMEOUT))?;
+    // Read whatever the client has already sent without waiting for more, as
+    // closing a socket with unread data may reset the connection before the
+    // client has seen the response
+    stream.set_nonblocking(true)?;
+    _ = stream.read(&mut [0; 1024]);
+    stream.set_nonblocking(false)?;
+    record.parsed();
+    record.response(&Response::from(error));
+    let result = stream.write_all(body);
+    record.finish(body.len(), &result);
+    metrics::observe(&record);
+    record.log()?;
+    result
+}
+
+/// Sends an error to a client whose connection is refused before a request
+/// has been read
+/// # Errors
+/// Returns an `io::Error` if unable to write to the `TcpStream`
+pub fn refuse(stream: TcpStream, error: ServerError) -> Result<(), io::Error> {
+    let body = format!("{} {}\r\n", error.status(), error.message());
+    refuse_with(stream, error, body.as_bytes())
 }
 
 /// Collects and parses command line arguments
@@ -147,7 +285,9 @@ pub fn options() -> Result<Matches, Fail> {
     let mut opts = Options::new();
     opts.optopt("c", "config", "Use NAME as config file", "NAME");
     opts.optflag("h", "help", "Print this help menu");
-    opts.optflag("v", "version", "Print the program version");
+    opts.optflagmulti("v", "verbose", "Log more detail, may be repeated");
+    opts.optflagmulti("q", "quiet", "Log less detail, may be repeated");
+    opts.optflag("V", "version", "Print the program version");
     opts.parse(&args[1..])
 }
 
@@ -164,7 +304,13 @@ pub fn usage() {
         -h, --help\n        \
         Print help information\n\
         \n\
-        -v, --version\n        \
+        -v, --verbose\n        \
+        Log more detail, may be repeated\n\
+        \n\
+        -q, --quiet\n        \
+        Log less detail, may be repeated\n\
+        \n\
+        -V, --version\n        \
         Print the program version\n\
         \n\
         -c, --config <config>\n        \
@@ -0,0 +1,148 @@
+//! Limits how many requests per second, and how many simultaneous connections,
+//! a single client ip may make. Each client has a token bucket which holds up
+//! to `burst` tokens and refills at `requests_per_second`, with one token being
+//! taken for every request. The server wide limits are checked as soon as a
+//! connection is accepted, before it is handed to the worker pool, while the
+//! limits for a virtual host are checked once the request has been parsed.
+//! Clients which exceed a limit are sent a "slow down" server error.
+
+use {
+    crate::{
+        config::{Level, RateLimit},
+        error::ServerError,
+        log::{anonymize, LogLevel},
+        request::Request,
+        CONFIG,
+    },
+    std::{
+        collections::HashMap,
+        net::IpAddr,
+        sync::{LazyLock, Mutex, PoisonError},
+        time::{Duration, Instant},
+    },
+};
+
+/// The number of tracked clients above which idle clients are forgotten
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// How long, in seconds, a client must be idle before it may be forgotten
+const IDLE_SECS: u64 = 600;
+
+/// Clients are tracked separately for the server wide limits and for each
+/// virtual host
+type Key = (Option<&'static str>, IpAddr);
+
+struct Client {
+    tokens: f64,
+    last: Instant,
+    connections: usize,
+    limited: bool,
+}
+
+#[derive(Default)]
+/// Tracks the state of every client which has recently connected
+pub struct Limiter {
+    clients: Mutex<HashMap<Key, Client>>,
+}
+
+/// The limiter shared by all listeners
+pub static LIMITER: LazyLock<Limiter> = LazyLock::new(Limiter::default);
+
+/// Counts a connection against a client's limit for as long as it is held
+pub struct Guard {
+    key: Key,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        LIMITER.release(&self.key);
+    }
+}
+
+impl Limiter {
+    fn admit(&self, key: Key, limit: &RateLimit) -> Result<Guard, ServerError> {
+        let mut clients = self.clients.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        if clients.len() > PRUNE_THRESHOLD {
+            clients.retain(|_, c| {
+                c.connections > 0 || now.duration_since(c.last) < Duration::from_secs(IDLE_SECS)
+            });
+        }
+        let burst = f64::from(limit.burst);
+        let client = clients.entry(key).or_insert(Client {
+            tokens: burst,
+            last: now,
+            connections: 0,
+            limited: false,
+        });
+        client.tokens = burst.min(
+            client.tokens
+                + now.duration_since(client.last).as_secs_f64() * limit.requests_per_second,
+        );
+        client.last = now;
+        let reason = if limit
+            .max_connections
+            .is_some_and(|m| client.connections >= m)
+        {
+            Some("too many connections")
+        } else if client.tokens < 1.0 {
+            Some("too many requests")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            // Only the first refusal is logged, until the client is admitted
+            // again, so that a misbehaving client can't flood the error log
+            let first = !client.limited;
+            client.limited = true;
+            drop(clients);
+            if first {
+                let scope = key.0.unwrap_or("server");
+                let msg = format!(
+                    "Rate limit ({scope}) exceeded by {}: {reason}",
+                    anonymize::client_ip(key.1)
+                );
+                if let Err(e) = msg.log_at(Level::Warn) {
+                    eprintln!("{e}");
+                }
+            }
+            return Err(ServerError::SlowDown);
+        }
+        client.tokens -= 1.0;
+        client.connections += 1;
+        client.limited = false;
+        Ok(Guard { key })
+    }
+
+    fn release(&self, key: &Key) {
+        let mut clients = self.clients.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(client) = clients.get_mut(key) {
+            client.connections = client.connections.saturating_sub(1);
+        }
+    }
+}
+
+/// Checks the server wide limits for a newly accepted connection
+/// # Errors
+/// Returns `ServerError::SlowDown` if the client has exceeded a limit
+pub fn admit(ip: IpAddr) -> Result<Option<Guard>, ServerError> {
+    match CONFIG.rate_limit {
+        Some(ref limit) => LIMITER.admit((None, ip), limit).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Checks the limits of the virtual host which a request is for
+/// # Errors
+/// Returns `ServerError::SlowDown` if the client has exceeded a limit
+pub fn admit_vhost(request: &Request) -> Result<Option<Guard>, ServerError> {
+    let Some(server) = CONFIG.vhost(&request.host) else {
+        return Ok(None);
+    };
+    match server.rate_limit {
+        Some(ref limit) => LIMITER
+            .admit((Some(server.name.as_str()), request.client_ip), limit)
+            .map(Some),
+        None => Ok(None),
+    }
+}
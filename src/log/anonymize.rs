@@ -0,0 +1,91 @@
+//! Anonymizes client ip addresses before they are written to the logs or
+//! passed to CGI programs. Addresses are either truncated, so that only the
+//! network a client belongs to is recorded, or replaced with a keyed hash. The
+//! key of the hash is generated at random and replaced every day (UTC), so
+//! that the requests of one client may be followed for at most a day. Access
+//! rules and rate limits always see the real address.
+
+use {
+    crate::{config::Anonymize, sign, CONFIG},
+    chrono::Utc,
+    hmac::Mac,
+    std::{
+        fs::File,
+        io::Read,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        process,
+        sync::{Mutex, PoisonError},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+/// The number of bytes of the hash which are kept
+const HASH_LEN: usize = 8;
+
+/// The key used to hash addresses, along with the day it was generated for
+static KEY: Mutex<Option<(i64, [u8; 32])>> = Mutex::new(None);
+
+/// Generates a new random key, falling back to the time and process id if
+/// the system's random source is unavailable
+fn generate() -> [u8; 32] {
+    let mut key = [0; 32];
+    if File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut key))
+        .is_err()
+    {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        key[..16].copy_from_slice(&nanos.to_le_bytes());
+        key[16..20].copy_from_slice(&process::id().to_le_bytes());
+    }
+    key
+}
+
+/// Replaces an address with a hex encoded hash, keyed with today's key
+fn hash(ip: IpAddr) -> String {
+    let today = Utc::now().timestamp().div_euclid(86_400);
+    let key = {
+        let mut key = KEY.lock().unwrap_or_else(PoisonError::into_inner);
+        match *key {
+            Some((day, k)) if day == today => k,
+            _ => {
+                let k = generate();
+                *key = Some((today, k));
+                k
+            }
+        }
+    };
+    let mac = match ip {
+        IpAddr::V4(ip) => sign::hmac(&key, &ip.octets()),
+        IpAddr::V6(ip) => sign::hmac(&key, &ip.octets()),
+    };
+    sign::hex(&mac.finalize().into_bytes()[..HASH_LEN])
+}
+
+/// Zeroes all but the network part of an address, being the first 24 bits of
+/// an ipv4 address or the first 48 bits of an ipv6 address
+fn truncate(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+/// Formats a client address as configured for the logs and CGI programs
+#[must_use]
+pub fn client_ip(ip: IpAddr) -> String {
+    // An ipv4 client of a listener bound to an ipv6 address appears as an
+    // ipv4 mapped address, which is anonymized as an ipv4 address
+    match CONFIG.anonymize_ips {
+        None => ip.to_string(),
+        Some(Anonymize::Truncate) => truncate(ip.to_canonical()).to_string(),
+        Some(Anonymize::Hash) => hash(ip.to_canonical()),
+    }
+}
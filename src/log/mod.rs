@@ -0,0 +1,463 @@
+#![allow(clippy::module_name_repetitions)]
+/// Anonymizing client ip addresses
+pub mod anonymize;
+/// The logger thread, which writes queued lines to the logs
+pub mod queue;
+/// Syslog and journald sinks
+pub mod sink;
+/// Access log lines built from a template
+pub mod template;
+/// Long lived log file writers, with reopening and rotation
+pub mod writer;
+
+use {
+    crate::{
+        config::{Level, LogFormat, LogSink, Server},
+        threadpool, Request, Response, CONFIG,
+    },
+    chrono::{DateTime, SecondsFormat, Utc},
+    serde_json::json,
+    std::{
+        borrow::Cow,
+        cell::Cell,
+        fmt::{Display, Write as _},
+        io,
+        net::IpAddr,
+        time::{Duration, Instant},
+    },
+};
+
+/// Logging access to the server
+pub trait Log {
+    type Error;
+
+    /// Writes server access to either the configured access log or stdout
+    /// # Errors
+    /// Returns an error (usually an `io::Error`) if unable to write to the
+    /// log file
+    fn log(&self) -> Result<(), Self::Error>;
+}
+
+/// Logging server errors
+pub trait LogError {
+    type Error;
+
+    /// Writes errors to either the configured error log or stderr
+    /// # Errors
+    /// Returns an error (usually an `io::Error`) if unable to write to the
+    /// log file
+    fn log_err(&self) -> Result<(), Self::Error>;
+}
+
+/// Logging messages at a level of severity
+pub trait LogLevel {
+    type Error;
+
+    /// Writes the message if messages at `level` are enabled. Errors and
+    /// warnings are written to the error log or stderr, and other messages to
+    /// the access log or stdout, unless the access log is written using a
+    /// template.
+    /// # Errors
+    /// Returns an error (usually an `io::Error`) if unable to write to the
+    /// log file
+    fn log_at(&self, level: Level) -> Result<(), Self::Error>;
+}
+
+thread_local! {
+    /// The virtual host whose request is being handled on this thread
+    static VHOST: Cell<Option<&'static Server>> = const { Cell::new(None) };
+}
+
+/// Sets the virtual host whose request is being handled on the current
+/// thread, so that errors are written to it's own error log if it has one.
+/// Should be reset to `None` once the request has been handled.
+pub fn set_vhost(server: Option<&'static Server>) {
+    VHOST.with(|v| v.set(server));
+}
+
+/// Whether messages at `level` are logged
+#[must_use]
+pub fn enabled(level: Level) -> bool {
+    level <= CONFIG.log_level
+}
+
+/// Whether messages at `level` belong in the error log. When records are
+/// written using a template the access log holds nothing else, so that it may
+/// be read by log analyzers, and every message belongs in the error log.
+fn in_error_log(level: Level) -> bool {
+    level <= Level::Warn || CONFIG.access_log_template.is_some()
+}
+
+/// The sink for messages at `level`, which is the log of the virtual host
+/// whose request is being handled if it has one of it's own
+fn sink_for(level: Level) -> Option<&'static LogSink> {
+    let server = VHOST.with(Cell::get);
+    if in_error_log(level) {
+        server
+            .and_then(|s| s.error_log.as_ref())
+            .or(CONFIG.error_log.as_ref())
+    } else {
+        server
+            .and_then(|s| s.access_log.as_ref())
+            .or(CONFIG.access_log.as_ref())
+    }
+}
+
+/// Quotes a logfmt value if it contains spaces, quotes, equals signs or
+/// control characters
+fn logfmt_value(value: &str) -> Cow<'_, str> {
+    if !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c == ' ' || c == '"' || c == '=' || c.is_control())
+    {
+        return Cow::Borrowed(value);
+    }
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => _ = write!(quoted, "\\u{{{:x}}}", u32::from(c)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    Cow::Owned(quoted)
+}
+
+/// A duration in milliseconds
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Formats a free form message as a line in the configured log format. In the
+/// text format, lines in the access log are terminated with a semicolon.
+fn message(level: Level, msg: &str) -> String {
+    let dt = Utc::now().to_rfc3339();
+    match CONFIG.log_format {
+        LogFormat::Text => {
+            let terminator = if in_error_log(level) { "" } else { ";" };
+            format!("{dt} [{level}] {msg}{terminator}\n")
+        }
+        LogFormat::Json => format!(
+            "{}\n",
+            json!({ "timestamp": dt, "level": level.to_string(), "message": msg })
+        ),
+        LogFormat::Logfmt => format!("time={dt} level={level} msg={}\n", logfmt_value(msg)),
+    }
+}
+
+impl<T> LogLevel for T
+where
+    T: Display,
+{
+    type Error = io::Error;
+
+    fn log_at(&self, level: Level) -> Result<(), Self::Error> {
+        if enabled(level) {
+            let line = message(level, &self.to_string());
+            queue::push(sink_for(level), line, vec![], level, in_error_log(level));
+        }
+        Ok(())
+    }
+}
+
+impl Log for std::string::String {
+    type Error = io::Error;
+
+    fn log(&self) -> Result<(), Self::Error> {
+        self.log_at(Level::Info)
+    }
+}
+
+impl Log for crate::Response {
+    type Error = io::Error;
+
+    fn log(&self) -> Result<(), Self::Error> {
+        match self {
+            Self::Success {
+                mimetype: _,
+                body: _,
+            }
+            | Self::Redirect { .. } => self.to_string().log()?,
+            Self::ClientError(_) | Self::ServerError(_) | Self::CustomError { .. } => {
+                self.log_err()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T> LogError for T
+where
+    T: Display,
+{
+    type Error = io::Error;
+
+    fn log_err(&self) -> Result<(), Self::Error> {
+        self.log_at(Level::Error)
+    }
+}
+
+/// A record of a single request, written to the access log as one line once
+/// the response has been sent. Handling a request is timed in three phases:
+/// reading and parsing the request, formulating the response, and sending it.
+pub struct Record {
+    /// When the request was accepted
+    pub timestamp: DateTime<Utc>,
+    /// When the request was accepted, for measuring how long it took
+    pub start: Instant,
+    /// The virtual host which served the request, or the requested host if
+    /// it named no virtual host
+    pub vhost: Option<String>,
+    /// The ip address of the client
+    pub client_ip: Option<IpAddr>,
+    /// The requested path
+    pub path: Option<String>,
+    /// Whether the request carried a query string
+    pub query: bool,
+    /// The status of the response
+    pub status: u8,
+    /// The mimetype of a successful response
+    pub mimetype: Option<String>,
+    /// Whether a redirect is permanent, if the response was a redirect
+    pub redirect: Option<bool>,
+    /// The number of bytes sent to the client, including the header
+    pub bytes: usize,
+    /// Whether sending the response to the client failed
+    pub write_failed: bool,
+    /// The number of bytes of content submitted by the client
+    pub bytes_received: usize,
+    /// How long the request took to handle
+    pub duration: Duration,
+    /// How long it took to read and parse the request
+    pub parse: Duration,
+    /// How long it took to formulate the response
+    pub handle: Duration,
+    /// How long it took to send the response
+    pub write: Duration,
+    /// When the last phase to be timed ended
+    lap: Instant,
+    /// The id of the worker thread which handled the request
+    pub worker: Option<usize>,
+    /// Where the record is written
+    sink: Option<&'static LogSink>,
+}
+
+impl Record {
+    /// Starts a record for a connection which has just been accepted
+    #[must_use]
+    pub fn start(client_ip: Option<IpAddr>) -> Self {
+        let start = Instant::now();
+        Self {
+            timestamp: Utc::now(),
+            start,
+            vhost: None,
+            client_ip,
+            path: None,
+            query: false,
+            status: 0,
+            mimetype: None,
+            redirect: None,
+            bytes: 0,
+            write_failed: false,
+            bytes_received: 0,
+            duration: Duration::ZERO,
+            parse: Duration::ZERO,
+            handle: Duration::ZERO,
+            write: Duration::ZERO,
+            lap: start,
+            worker: threadpool::worker_id(),
+            sink: CONFIG.access_log.as_ref(),
+        }
+    }
+
+    /// Fills in the details of the request, and the virtual host serving it.
+    /// The record is written to the access log of the virtual host if it has
+    /// one of it's own.
+    pub fn request(&mut self, request: &Request, server: Option<&'static Server>) {
+        self.vhost = Some(server.map_or(&request.host, |s| &s.name).clone());
+        if let Some(sink) = server.and_then(|s| s.access_log.as_ref()) {
+            self.sink = Some(sink);
+        }
+        self.client_ip = Some(request.client_ip);
+        self.path = Some(request.path.clone());
+        self.query = request.query.is_some();
+        self.bytes_received = request.length;
+    }
+
+    /// The time since the last phase ended, which starts the next phase
+    fn lap(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now - self.lap;
+        self.lap = now;
+        elapsed
+    }
+
+    /// Ends the parse phase, once the request has been read whether or not it
+    /// was valid
+    pub fn parsed(&mut self) {
+        self.parse = self.lap();
+    }
+
+    /// Fills in the details of the response, which is about to be sent, and
+    /// ends the handle phase
+    pub fn response(&mut self, response: &Response) {
+        self.handle = self.lap();
+        self.status = response.status();
+        match response {
+            Response::Success { mimetype, .. } => self.mimetype = Some(mimetype.clone()),
+            Response::Redirect { permanent, .. } => self.redirect = Some(*permanent),
+            _ => {}
+        }
+    }
+
+    /// The kind of redirect sent, if the response was a redirect
+    #[must_use]
+    pub fn redirect_kind(&self) -> Option<&'static str> {
+        self.redirect
+            .map(|permanent| if permanent { "permanent" } else { "temporary" })
+    }
+
+    /// Records the number of bytes sent and stops the clock, ending the write
+    /// phase. If sending the response failed, no bytes are counted as sent.
+    pub fn finish(&mut self, bytes: usize, result: &io::Result<()>) {
+        self.write_failed = result.is_err();
+        self.bytes = if self.write_failed { 0 } else { bytes };
+        self.write = self.lap();
+        self.duration = self.start.elapsed();
+    }
+
+    /// The fields of the record which have a value, named as they are stored
+    /// in the systemd journal
+    #[must_use]
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let fields = [
+            ("VHOST", self.vhost.clone()),
+            ("CLIENT_IP", self.client_ip.map(anonymize::client_ip)),
+            ("PATH", self.path.clone()),
+            ("QUERY", Some(self.query.to_string())),
+            ("STATUS", Some(self.status.to_string())),
+            ("MIMETYPE", self.mimetype.clone()),
+            ("REDIRECT", self.redirect_kind().map(String::from)),
+            ("BYTES", Some(self.bytes.to_string())),
+            ("WRITE_FAILED", Some(self.write_failed.to_string())),
+            ("BYTES_RECEIVED", Some(self.bytes_received.to_string())),
+            ("DURATION_MS", Some(format!("{:.3}", millis(self.duration)))),
+            ("PARSE_MS", Some(format!("{:.3}", millis(self.parse)))),
+            ("HANDLE_MS", Some(format!("{:.3}", millis(self.handle)))),
+            ("WRITE_MS", Some(format!("{:.3}", millis(self.write)))),
+            ("WORKER", self.worker.map(|w| w.to_string())),
+        ];
+        fields
+            .into_iter()
+            .filter_map(|(k, v)| v.map(|v| (k, v)))
+            .collect()
+    }
+
+    /// Formats the record as a line using the configured access log template,
+    /// or in the configured log format if there is no template
+    #[must_use]
+    pub fn format(&self) -> String {
+        if let Some(template) = &CONFIG.access_log_template {
+            return self.template(template);
+        }
+        let timestamp = self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true);
+        let client_ip = self.client_ip.map(anonymize::client_ip);
+        let [duration_ms, parse_ms, handle_ms, write_ms] =
+            [self.duration, self.parse, self.handle, self.write].map(millis);
+        match CONFIG.log_format {
+            LogFormat::Text => {
+                let worker = self.worker.map(|w| w.to_string());
+                let redirect = self
+                    .redirect_kind()
+                    .map_or_else(String::new, |kind| format!(" redirect {kind}"));
+                let failed = if self.write_failed {
+                    " write failed"
+                } else {
+                    ""
+                };
+                format!(
+                    "{timestamp} {} {} \"{}{}\" {} \"{}\" {} {duration_ms:.3}ms worker {} \
+                     received {} parse {parse_ms:.3}ms handle {handle_ms:.3}ms \
+                     write {write_ms:.3}ms{redirect}{failed};\n",
+                    client_ip.as_deref().unwrap_or("-"),
+                    self.vhost.as_deref().unwrap_or("-"),
+                    self.path.as_deref().unwrap_or("-"),
+                    if self.query { "?" } else { "" },
+                    self.status,
+                    self.mimetype.as_deref().unwrap_or("-"),
+                    self.bytes,
+                    worker.as_deref().unwrap_or("-"),
+                    self.bytes_received,
+                )
+            }
+            LogFormat::Json => format!(
+                "{}\n",
+                json!({
+                    "timestamp": timestamp,
+                    "vhost": self.vhost,
+                    "client_ip": client_ip,
+                    "path": self.path,
+                    "query": self.query,
+                    "status": self.status,
+                    "mimetype": self.mimetype,
+                    "redirect": self.redirect_kind(),
+                    "bytes": self.bytes,
+                    "write_failed": self.write_failed,
+                    "bytes_received": self.bytes_received,
+                    "duration_ms": (duration_ms * 1000.0).round() / 1000.0,
+                    "parse_ms": (parse_ms * 1000.0).round() / 1000.0,
+                    "handle_ms": (handle_ms * 1000.0).round() / 1000.0,
+                    "write_ms": (write_ms * 1000.0).round() / 1000.0,
+                    "worker": self.worker,
+                })
+            ),
+            LogFormat::Logfmt => {
+                let fields = [
+                    ("time", Some(timestamp)),
+                    ("vhost", self.vhost.clone()),
+                    ("client_ip", client_ip),
+                    ("path", self.path.clone()),
+                    ("query", Some(self.query.to_string())),
+                    ("status", Some(self.status.to_string())),
+                    ("mimetype", self.mimetype.clone()),
+                    ("redirect", self.redirect_kind().map(String::from)),
+                    ("bytes", Some(self.bytes.to_string())),
+                    ("write_failed", Some(self.write_failed.to_string())),
+                    ("bytes_received", Some(self.bytes_received.to_string())),
+                    ("duration_ms", Some(format!("{duration_ms:.3}"))),
+                    ("parse_ms", Some(format!("{parse_ms:.3}"))),
+                    ("handle_ms", Some(format!("{handle_ms:.3}"))),
+                    ("write_ms", Some(format!("{write_ms:.3}"))),
+                    ("worker", self.worker.map(|w| w.to_string())),
+                ];
+                let mut line = fields
+                    .iter()
+                    .map(|(k, v)| format!("{k}={}", logfmt_value(v.as_deref().unwrap_or(""))))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                line.push('\n');
+                line
+            }
+        }
+    }
+}
+
+impl Log for Record {
+    type Error = io::Error;
+
+    fn log(&self) -> Result<(), Self::Error> {
+        let sink = self.sink;
+        // Only the journal stores the fields of a record separately
+        let fields = if matches!(sink, Some(LogSink::Journald { .. })) {
+            self.fields()
+        } else {
+            vec![]
+        };
+        queue::push(sink, self.format(), fields, Level::Info, false);
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+//! The logger thread. Lines are passed to it through a bounded queue, so that
+//! a slow disk does not hold up the worker threads, and are written in the
+//! order in which they were queued. The log files are flushed at a regular
+//! interval rather than after every line. Should the queue fill up, further
+//! lines are dropped and counted until there is room for them again.
+
+use {
+    super::{sink, writer},
+    crate::config::{Level, LogSink},
+    std::{
+        io::{self, Write},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+            LazyLock,
+        },
+        thread,
+        time::{Duration, Instant},
+    },
+};
+
+/// The number of lines which may be waiting to be written
+const CAPACITY: usize = 4096;
+
+/// How often the log files are flushed
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long `flush` waits for the logger thread to catch up
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The number of lines dropped because the queue was full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// The sending half of the queue, or `None` if the logger thread could not be
+/// started, in which case lines are written directly
+static QUEUE: LazyLock<Option<SyncSender<Entry>>> = LazyLock::new(|| {
+    let (sender, receiver) = mpsc::sync_channel(CAPACITY);
+    thread::Builder::new()
+        .name(String::from("logger"))
+        .spawn(move || run(&receiver))
+        .ok()
+        .map(|_| sender)
+});
+
+/// An item in the queue
+enum Entry {
+    /// A line for `sink`, or for stdout or stderr if there is no sink, along
+    /// with the fields of the line for sinks which store them separately
+    Line {
+        sink: Option<&'static LogSink>,
+        line: String,
+        fields: Vec<(&'static str, String)>,
+        level: Level,
+        /// Whether the line belongs in the error log rather than the access
+        /// log, and so falls back to stderr
+        error: bool,
+    },
+    /// Asks for everything queued so far to be flushed, with the sender
+    /// notified once it has been
+    Flush(mpsc::Sender<()>),
+}
+
+/// Writes a line to `sink`, or to stdout or stderr if no sink is configured or
+/// it can't be written to
+fn write(sink: Option<&LogSink>, line: &str, fields: &[(&str, String)], level: Level, error: bool) {
+    let fallback = |line: &str| {
+        if error {
+            eprint!("{line}");
+        } else {
+            print!("{line}");
+        }
+    };
+    let res = match sink {
+        Some(LogSink::File(path)) => writer::write(path, line),
+        Some(sink) => sink::write(sink, line, fields, level),
+        None => {
+            fallback(line);
+            return;
+        }
+    };
+    if let Err(e) = res {
+        if let Some(sink) = sink {
+            eprintln!("{sink}: {e}");
+        }
+        fallback(line);
+    }
+}
+
+/// Flushes the log files and stdout, first noting any lines which have been
+/// dropped since `reported` in the error log
+fn flush_all(reported: &mut u64) {
+    let dropped = DROPPED.load(Ordering::Relaxed);
+    if dropped > *reported {
+        let msg = format!(
+            "{} log messages were dropped as the log queue was full",
+            dropped - *reported
+        );
+        write(
+            super::sink_for(Level::Warn),
+            &super::message(Level::Warn, &msg),
+            &[],
+            Level::Warn,
+            true,
+        );
+        *reported = dropped;
+    }
+    writer::flush();
+    _ = io::stdout().flush();
+}
+
+/// Receives lines from the queue and writes them, until the queue is closed
+fn run(receiver: &Receiver<Entry>) {
+    let mut last_flush = Instant::now();
+    let mut reported = 0;
+    loop {
+        let timeout = FLUSH_INTERVAL.saturating_sub(last_flush.elapsed());
+        match receiver.recv_timeout(timeout) {
+            Ok(Entry::Line {
+                sink,
+                line,
+                fields,
+                level,
+                error,
+            }) => write(sink, &line, &fields, level, error),
+            Ok(Entry::Flush(done)) => {
+                flush_all(&mut reported);
+                last_flush = Instant::now();
+                _ = done.send(());
+                continue;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if last_flush.elapsed() >= FLUSH_INTERVAL {
+            flush_all(&mut reported);
+            last_flush = Instant::now();
+        }
+    }
+    flush_all(&mut reported);
+}
+
+/// Queues a line to be written to `sink`, or to stdout or stderr depending on
+/// which log it belongs in if there is no sink. The line is dropped if the
+/// queue is full.
+pub fn push(
+    sink: Option<&'static LogSink>,
+    line: String,
+    fields: Vec<(&'static str, String)>,
+    level: Level,
+    error: bool,
+) {
+    // Without a logger thread, lines are written directly
+    let Some(queue) = QUEUE.as_ref() else {
+        write(sink, &line, &fields, level, error);
+        writer::flush();
+        return;
+    };
+    let entry = Entry::Line {
+        sink,
+        line,
+        fields,
+        level,
+        error,
+    };
+    match queue.try_send(entry) {
+        Ok(()) | Err(TrySendError::Disconnected(Entry::Flush(_))) => {}
+        Err(TrySendError::Full(_)) => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(TrySendError::Disconnected(Entry::Line { line, fields, .. })) => {
+            write(sink, &line, &fields, level, error);
+            writer::flush();
+        }
+    }
+}
+
+/// Waits until every line queued so far has been written and the log files
+/// flushed, or for at most a few seconds
+pub fn flush() {
+    let Some(queue) = QUEUE.as_ref() else {
+        return;
+    };
+    let (done, wait) = mpsc::channel();
+    if queue.send(Entry::Flush(done)).is_ok() {
+        _ = wait.recv_timeout(FLUSH_TIMEOUT);
+    }
+}
+
+/// The number of log lines dropped so far because the queue was full
+#[must_use]
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
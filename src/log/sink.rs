@@ -0,0 +1,205 @@
+//! Log sinks other than files. The local syslog daemon is sent RFC 5424
+//! messages, while the systemd journal is sent structured entries using it's
+//! native protocol, so that the fields of each access log record may be
+//! searched on. Both are reached through a Unix datagram socket.
+
+use {
+    crate::config::{Facility, Level, LogSink},
+    chrono::{SecondsFormat, Utc},
+    std::{ffi::CStr, io, os::unix::net::UnixDatagram, path::Path, process, sync::LazyLock},
+};
+
+/// The default socket of the syslog daemon
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// The default socket of the systemd journal
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// The name messages are logged under
+const IDENTIFIER: &str = env!("CARGO_PKG_NAME");
+
+/// A socket shared by every sink, as datagrams carry their destination
+static SOCKET: LazyLock<Option<UnixDatagram>> = LazyLock::new(|| UnixDatagram::unbound().ok());
+
+/// The host name sent to the syslog daemon
+static HOSTNAME: LazyLock<String> = LazyLock::new(|| {
+    let mut buf = [0; 256];
+    // Safety: the buffer is large enough for any host name and is always nul
+    // terminated, as it's final byte is never written to
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len() - 1) };
+    if res != 0 {
+        return String::from("-");
+    }
+    unsafe { CStr::from_ptr(buf.as_ptr()) }
+        .to_str()
+        .ok()
+        .filter(|h| !h.is_empty())
+        .map_or_else(|| String::from("-"), String::from)
+});
+
+fn send(datagram: &[u8], socket: &Path) -> io::Result<()> {
+    match SOCKET.as_ref() {
+        Some(s) => s.send_to(datagram, socket)?,
+        None => UnixDatagram::unbound()?.send_to(datagram, socket)?,
+    };
+    Ok(())
+}
+
+/// The syslog severity of a level
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Formats a line as an RFC 5424 syslog message
+fn syslog(facility: Facility, line: &str, level: Level) -> String {
+    let msgid = if level <= Level::Warn {
+        "error"
+    } else {
+        "access"
+    };
+    format!(
+        "<{}>1 {} {} {IDENTIFIER} {} {msgid} - {}",
+        facility.code() * 8 + severity(level),
+        Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+        *HOSTNAME,
+        process::id(),
+        line.trim_end(),
+    )
+}
+
+/// Appends a field to a journal entry, using the binary form if the value
+/// spans more than one line
+fn field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// Builds a journal entry from a line and it's fields
+fn journal(line: &str, fields: &[(&str, String)], level: Level) -> Vec<u8> {
+    let mut entry = Vec::new();
+    field(&mut entry, "MESSAGE", line.trim_end());
+    field(&mut entry, "PRIORITY", &severity(level).to_string());
+    field(&mut entry, "SYSLOG_IDENTIFIER", IDENTIFIER);
+    for (name, value) in fields {
+        field(&mut entry, name, value);
+    }
+    entry
+}
+
+/// Sends a line to a syslog or journald sink, with the severity of it's level.
+/// The fields are only used by the journal.
+/// # Errors
+/// Returns an `io::Error` if the line can not be sent, or if `sink` is a file
+pub fn write(
+    sink: &LogSink,
+    line: &str,
+    fields: &[(&str, String)],
+    level: Level,
+) -> io::Result<()> {
+    match sink {
+        LogSink::Syslog { facility, socket } => {
+            let socket = socket.as_deref().unwrap_or(Path::new(SYSLOG_SOCKET));
+            send(syslog(*facility, line, level).as_bytes(), socket)
+        }
+        LogSink::Journald { socket } => {
+            let socket = socket.as_deref().unwrap_or(Path::new(JOURNALD_SOCKET));
+            send(&journal(line, fields, level), socket)
+        }
+        LogSink::File(_) => Err(io::ErrorKind::Unsupported.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::path::PathBuf, tempfile::TempDir};
+
+    /// Binds a socket in a new directory, standing in for a daemon
+    fn daemon() -> (TempDir, PathBuf, UnixDatagram) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("socket");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        (dir, path, socket)
+    }
+
+    fn receive(socket: &UnixDatagram) -> Vec<u8> {
+        let mut buf = [0; 4096];
+        let len = socket.recv(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn syslog_header() {
+        let (_dir, path, socket) = daemon();
+        let sink = LogSink::Syslog {
+            facility: Facility::Local0,
+            socket: Some(path),
+        };
+        write(&sink, "localhost / 2\n", &[], Level::Info).unwrap();
+        let message = String::from_utf8(receive(&socket)).unwrap();
+        let parts: Vec<&str> = message.splitn(8, ' ').collect();
+        // Local0 (16) * 8 + Informational (6)
+        assert_eq!(parts[0], "<134>1");
+        assert!(chrono::DateTime::parse_from_rfc3339(parts[1]).is_ok());
+        assert_eq!(parts[2], *HOSTNAME);
+        assert_eq!(parts[3], IDENTIFIER);
+        assert_eq!(parts[4], process::id().to_string());
+        assert_eq!(parts[5], "access");
+        assert_eq!(parts[6], "-");
+        assert_eq!(parts[7], "localhost / 2");
+    }
+
+    #[test]
+    fn syslog_severity() {
+        let (_dir, path, socket) = daemon();
+        let sink = LogSink::Syslog {
+            facility: Facility::Daemon,
+            socket: Some(path),
+        };
+        write(&sink, "failed", &[], Level::Error).unwrap();
+        let message = String::from_utf8(receive(&socket)).unwrap();
+        // Daemon (3) * 8 + Error (3)
+        assert!(message.starts_with("<27>1 "));
+        assert!(message.ends_with(" error - failed"));
+        write(&sink, "routing", &[], Level::Debug).unwrap();
+        let message = String::from_utf8(receive(&socket)).unwrap();
+        assert!(message.starts_with("<31>1 "));
+    }
+
+    #[test]
+    fn journal_fields() {
+        let (_dir, path, socket) = daemon();
+        let sink = LogSink::Journald { socket: Some(path) };
+        let fields = [("STATUS", String::from("2"))];
+        write(&sink, "localhost / 2\n", &fields, Level::Warn).unwrap();
+        let entry = String::from_utf8(receive(&socket)).unwrap();
+        let expected = format!(
+            "MESSAGE=localhost / 2\nPRIORITY=4\nSYSLOG_IDENTIFIER={IDENTIFIER}\nSTATUS=2\n"
+        );
+        assert_eq!(entry, expected);
+    }
+
+    #[test]
+    fn journal_binary_field() {
+        let (_dir, path, socket) = daemon();
+        let sink = LogSink::Journald { socket: Some(path) };
+        let fields = [("PATH", String::from("/a\nb"))];
+        write(&sink, "line", &fields, Level::Info).unwrap();
+        let entry = receive(&socket);
+        let mut expected = b"PATH\n".to_vec();
+        expected.extend_from_slice(&4u64.to_le_bytes());
+        expected.extend_from_slice(b"/a\nb\n");
+        assert!(entry.ends_with(&expected));
+    }
+}
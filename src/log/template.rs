@@ -0,0 +1,89 @@
+//! Access log lines built from a template, so that the logs can be read by
+//! existing log analyzers. Each placeholder in the template, such as `{path}`,
+//! is replaced by that detail of the request, or by `-` if it has no value.
+//! Braces which do not enclose a known placeholder are written as they are.
+//! The placeholders are listed in the README.
+
+use {
+    super::{anonymize, millis, Record},
+    crate::config::AccessTemplate,
+    chrono::SecondsFormat,
+    std::fmt::Write as _,
+};
+
+/// The Common Log Format. Spartan has no request methods, so every request is
+/// written as a `GET`.
+pub const COMMON: &str =
+    "{client_ip} - - [{time_clf}] \"GET {path}{query} SPARTAN\" {status_code} {bytes}";
+
+/// Escapes quotes, backslashes and control characters, which could otherwise
+/// be used to forge the fields of a line
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => _ = write!(escaped, "\\x{:02x}", u32::from(c)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl Record {
+    /// The value of a placeholder, or `None` if there is no such placeholder
+    fn placeholder(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "client_ip" => self.client_ip.map(anonymize::client_ip),
+            "time" => Some(self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            "time_clf" => Some(self.timestamp.format("%d/%b/%Y:%H:%M:%S %z").to_string()),
+            "host" => self.vhost.as_deref().map(escape),
+            "path" => self.path.as_deref().map(escape),
+            "query" => Some(String::from(if self.query { "?" } else { "" })),
+            "status" => Some(self.status.to_string()),
+            "status_code" => Some((u16::from(self.status) * 100).to_string()),
+            "mimetype" => self.mimetype.as_deref().map(escape),
+            "redirect" => self.redirect_kind().map(String::from),
+            "bytes" => Some(self.bytes.to_string()),
+            "write_failed" => Some(self.write_failed.to_string()),
+            "bytes_received" => Some(self.bytes_received.to_string()),
+            "duration" => Some(format!("{:.3}", millis(self.duration))),
+            "parse" => Some(format!("{:.3}", millis(self.parse))),
+            "handle" => Some(format!("{:.3}", millis(self.handle))),
+            "write" => Some(format!("{:.3}", millis(self.write))),
+            "worker" => self.worker.map(|w| w.to_string()),
+            _ => return None,
+        };
+        Some(value.unwrap_or_else(|| String::from("-")))
+    }
+
+    /// Formats the record as a line by filling in the placeholders of a
+    /// template
+    #[must_use]
+    pub fn template(&self, template: &AccessTemplate) -> String {
+        let template = match template {
+            AccessTemplate::Common => COMMON,
+            AccessTemplate::Template(t) => t,
+        };
+        let mut line = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            line.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let value = after
+                .find('}')
+                .and_then(|end| self.placeholder(&after[..end]).map(|v| (end, v)));
+            if let Some((end, value)) = value {
+                line.push_str(&value);
+                rest = &after[end + 1..];
+            } else {
+                line.push('{');
+                rest = after;
+            }
+        }
+        line.push_str(rest);
+        line.push('\n');
+        line
+    }
+}
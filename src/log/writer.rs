@@ -0,0 +1,211 @@
+//! Long lived writers for the log files. Each log file is opened once and kept
+//! open, rather than being opened for every line. On receiving `SIGUSR1` every
+//! log file is closed and reopened, so that an external tool such as logrotate
+//! may move the logs out of the way and then signal the server. Alternatively
+//! the logs may be rotated by the server itself, once they reach a given size
+//! or at a regular interval. Lines are buffered until the logs are flushed.
+
+use {
+    crate::{
+        config::{Interval, Rotation},
+        CONFIG,
+    },
+    chrono::{DateTime, Utc},
+    flate2::{write::GzEncoder, Compression},
+    std::{
+        collections::HashMap,
+        ffi::OsString,
+        fs::{self, File, OpenOptions},
+        io::{self, BufWriter, Write},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            LazyLock, Mutex, PoisonError,
+        },
+    },
+};
+
+/// Incremented each time `SIGUSR1` is received. A writer which has seen an
+/// older value reopens it's file before writing.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// The open log files, by path
+static WRITERS: LazyLock<Mutex<HashMap<PathBuf, Writer>>> = LazyLock::new(Mutex::default);
+
+extern "C" fn reopen(_signal: libc::c_int) {
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Installs a handler for `SIGUSR1` which causes every log file to be
+/// reopened
+/// # Errors
+/// Returns the last OS error if the handler could not be installed
+pub fn install_handler() -> io::Result<()> {
+    let handler = reopen as extern "C" fn(libc::c_int);
+    // Safety: the handler does nothing but increment an atomic counter, which
+    // is safe to do from within a signal handler
+    if unsafe { libc::signal(libc::SIGUSR1, handler as libc::sighandler_t) } == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Numbers the rotation periods since the epoch, so that two times fall in
+/// the same period if they have the same number
+fn period(time: DateTime<Utc>, interval: Interval) -> i64 {
+    let secs = time.timestamp();
+    match interval {
+        Interval::Hourly => secs.div_euclid(3600),
+        Interval::Daily => secs.div_euclid(86_400),
+        // The epoch fell on a Thursday, so shift the days such that each
+        // period begins on a Monday
+        Interval::Weekly => (secs.div_euclid(86_400) + 3).div_euclid(7),
+    }
+}
+
+/// Appends `suffix` to the file name of `path`
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path);
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Compresses the file at `src` into a new file at `dst`
+fn gzip(src: &Path, dst: &Path) -> io::Result<()> {
+    let mut input = File::open(src)?;
+    let mut encoder = GzEncoder::new(File::create(dst)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// A single open log file
+struct Writer {
+    path: PathBuf,
+    file: Option<BufWriter<File>>,
+    /// The current size of the file in bytes
+    size: u64,
+    /// When the file was started, or last written to before being opened
+    started: DateTime<Utc>,
+    /// The value of `GENERATION` when the file was opened
+    generation: usize,
+}
+
+impl Writer {
+    fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            file: None,
+            size: 0,
+            started: Utc::now(),
+            generation: GENERATION.load(Ordering::Relaxed),
+        }
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            let meta = file.metadata()?;
+            self.size = meta.len();
+            // A log which already has content belongs to the period in which
+            // it was last written to
+            self.started = match meta.modified() {
+                Ok(m) if self.size > 0 => DateTime::from(m),
+                _ => Utc::now(),
+            };
+            self.file = Some(BufWriter::new(file));
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        match self.file.take() {
+            Some(mut file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the file should be rotated before `len` more bytes are written
+    fn due(&self, rotation: &Rotation, len: usize) -> bool {
+        let full = rotation
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + len as u64 > max);
+        let expired = rotation
+            .interval
+            .is_some_and(|i| period(self.started, i) != period(Utc::now(), i));
+        full || expired
+    }
+
+    fn rotate(&mut self, rotation: &Rotation) -> io::Result<()> {
+        self.close()?;
+        if rotation.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        let ext = if rotation.compress { ".gz" } else { "" };
+        let name = |n: usize| with_suffix(&self.path, &format!(".{n}{ext}"));
+        match fs::remove_file(name(rotation.keep)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        for n in (1..rotation.keep).rev() {
+            if name(n).exists() {
+                fs::rename(name(n), name(n + 1))?;
+            }
+        }
+        let first = with_suffix(&self.path, ".1");
+        fs::rename(&self.path, &first)?;
+        if rotation.compress {
+            gzip(&first, &name(1))?;
+            fs::remove_file(&first)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        let generation = GENERATION.load(Ordering::Relaxed);
+        if generation != self.generation {
+            self.generation = generation;
+            self.close()?;
+        }
+        self.open()?;
+        if let Some(ref rotation) = CONFIG.log_rotation {
+            if self.due(rotation, line.len()) {
+                self.rotate(rotation)?;
+                self.open()?;
+            }
+        }
+        let Some(ref mut file) = self.file else {
+            return Err(io::ErrorKind::NotConnected.into());
+        };
+        file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Appends a line to the log file at `path`, opening it if this is the first
+/// line written to it. The line is buffered until the next call to `flush`.
+/// # Errors
+/// Returns an `io::Error` if the file can not be opened, rotated or written to
+pub fn write(path: &Path, line: &str) -> io::Result<()> {
+    let mut writers = WRITERS.lock().unwrap_or_else(PoisonError::into_inner);
+    writers
+        .entry(path.to_path_buf())
+        .or_insert_with(|| Writer::new(path))
+        .write(line.as_bytes())
+}
+
+/// Flushes every open log file, printing any errors to stderr
+pub fn flush() {
+    let mut writers = WRITERS.lock().unwrap_or_else(PoisonError::into_inner);
+    for writer in writers.values_mut() {
+        if let Some(ref mut file) = writer.file {
+            if let Err(e) = file.flush() {
+                eprintln!("{}: {e}", writer.path.display());
+            }
+        }
+    }
+}
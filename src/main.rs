@@ -2,19 +2,90 @@
 
 use {
     agis::{
-        log::{Log, LogError},
-        CONFIG,
+        config::Level,
+        error::ServerError,
+        limit,
+        log::{self, Log, LogError, LogLevel},
+        metrics, CONFIG,
     },
     std::{
-        env,
-        net::TcpListener,
+        env, io,
+        net::{TcpListener, TcpStream},
         num::NonZeroUsize,
         process,
-        sync::{mpsc::channel, Arc, Mutex},
+        sync::{
+            mpsc::{channel, sync_channel},
+            Arc, Mutex,
+        },
         thread,
     },
 };
 
+/// The number of refused connections which may be waiting to be sent their
+/// error, beyond which further refused connections are closed without one
+const REFUSE_QUEUE: usize = 64;
+
+/// Accepts incoming connections on a separate thread, passing each stream off
+/// to the worker pool to be processed by `handler`. Connections from clients
+/// which have exceeded their rate limit are passed to `refuse` instead, on a
+/// thread of it's own so that a slow client can't hold up accepting.
+fn serve(
+    listener: TcpListener,
+    pool: Arc<Mutex<agis::ThreadPool>>,
+    handler: fn(TcpStream) -> io::Result<()>,
+    refuse: fn(TcpStream, ServerError) -> io::Result<()>,
+) {
+    let (refusals, refused) = sync_channel::<(TcpStream, ServerError)>(REFUSE_QUEUE);
+    thread::spawn(move || {
+        for (stream, e) in refused {
+            if let Err(e) = refuse(stream, e) {
+                if let Err(e) = e.log_err() {
+                    eprintln!("{e}");
+                }
+            }
+        }
+    });
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    if let Err(e) = e.log_err() {
+                        eprintln!("{e}");
+                    }
+                    continue;
+                }
+            };
+            let guard = match stream.peer_addr().map(|addr| limit::admit(addr.ip())) {
+                Ok(Ok(guard)) => guard,
+                Ok(Err(e)) => {
+                    metrics::connection_rejected();
+                    // If the queue is full the connection is dropped, closing it
+                    _ = refusals.try_send((stream, e));
+                    continue;
+                }
+                Err(e) => {
+                    if let Err(e) = e.log_err() {
+                        eprintln!("{e}");
+                    }
+                    continue;
+                }
+            };
+            if let Ok(pool) = pool.try_lock() {
+                pool.execute(move || {
+                    // Hold the guard until the connection has been handled
+                    let _guard = guard;
+                    if let Err(e) = handler(stream) {
+                        if let Err(e) = e.log_err() {
+                            eprintln!("{e}");
+                        }
+                    }
+                });
+            }
+        }
+    });
+}
+
 fn main() -> std::io::Result<()> {
     // Get any CLI flags
     let matches = match agis::options() {
@@ -29,7 +100,7 @@ fn main() -> std::io::Result<()> {
         agis::usage();
         process::exit(0);
     }
-    if matches.opt_present("v") {
+    if matches.opt_present("V") {
         agis::version();
         process::exit(0);
     }
@@ -41,8 +112,23 @@ fn main() -> std::io::Result<()> {
         eprintln!("{prog} must be started as the root user.");
         process::exit(1);
     }
+    let result = run();
+    // Whether the server shut down or failed while starting, make sure that
+    // everything queued so far reaches the logs before the process exits
+    log::queue::flush();
+    result
+}
+
+/// Creates the logs, binds the listeners and drops privileges, then serves
+/// connections until the process receives a termination signal
+fn run() -> io::Result<()> {
     let user = CONFIG.getpwnam()?;
     let group = CONFIG.getgrnam()?;
+    // The logs are created before anything is logged, as once a log file has
+    // been opened it is kept open
+    unsafe {
+        agis::init_logs((*user).pw_uid, (*group).gr_gid)?;
+    }
 
     let _msg = "Starting up thread pool".to_string().log();
     let threads = NonZeroUsize::new(CONFIG.threads).unwrap();
@@ -63,65 +149,50 @@ fn main() -> std::io::Result<()> {
         }
         None => None,
     };
-    // Both of these functions call into libc, group them together so we only
-    // have one unsafe block
+    // The Gopher listener serves a single vhost, sharing the worker pool with
+    // the Spartan listeners
+    let gopher_listener = match CONFIG.gopher {
+        Some(ref g) => {
+            let l = TcpListener::bind(format!("{}:{}", g.address.ip, g.address.port))?;
+            let _msg = format!(
+                "Binding Gopher listener to address {} on port {}",
+                g.address.ip, g.address.port
+            )
+            .log();
+            Some(l)
+        }
+        None => None,
+    };
+    let metrics_listener = CONFIG.metrics.as_ref().map(metrics::bind).transpose()?;
     unsafe {
-        agis::init_logs((*user).pw_uid, (*group).gr_gid)?;
         agis::privdrop(user, group)?;
     }
+    if let Err(e) = log::writer::install_handler() {
+        let _msg = format!("Unable to install SIGUSR1 handler: {e}").log_at(Level::Warn);
+    }
     let _msg = "Privileges dropped, listening for incoming connections"
         .to_string()
         .log();
+    if let Some(ls) = metrics_listener {
+        metrics::serve(ls)?;
+    }
     if let Some(ls) = listener1 {
-        let pool = Arc::clone(&pool);
-        thread::spawn(move || {
-            for stream in ls.incoming() {
-                let stream = match stream {
-                    Ok(s) => s,
-                    Err(e) => {
-                        if let Err(e) = e.log_err() {
-                            eprintln!("{e}");
-                        }
-                        continue;
-                    }
-                };
-                if let Ok(pool) = pool.try_lock() {
-                    pool.execute(|| {
-                        if let Err(e) = agis::handle_connection(stream) {
-                            if let Err(e) = e.log_err() {
-                                eprintln!("{e}");
-                            }
-                        }
-                    });
-                }
-            }
-        });
+        serve(ls, Arc::clone(&pool), agis::handle_connection, agis::refuse);
     }
-    {
-        let pool = Arc::clone(&pool);
-        thread::spawn(move || {
-            for stream in listener.incoming() {
-                let stream = match stream {
-                    Ok(s) => s,
-                    Err(e) => {
-                        if let Err(e) = e.log_err() {
-                            eprintln!("{e}");
-                        }
-                        continue;
-                    }
-                };
-                if let Ok(pool) = pool.try_lock() {
-                    pool.execute(|| {
-                        if let Err(e) = agis::handle_connection(stream) {
-                            if let Err(e) = e.log_err() {
-                                eprintln!("{e}");
-                            }
-                        }
-                    });
-                }
-            }
-        });
+    if let Some(ls) = gopher_listener {
+        serve(
+            ls,
+            Arc::clone(&pool),
+            agis::gopher::handle_connection,
+            agis::gopher::refuse,
+        );
     }
+    serve(
+        listener,
+        Arc::clone(&pool),
+        agis::handle_connection,
+        agis::refuse,
+    );
     let (tx, rx) = channel();
     ctrlc::set_handler(move || {
         tx.send(()).expect("Cannot send termination signal");
@@ -0,0 +1,347 @@
+//! Metrics in the Prometheus text exposition format, served over HTTP on a
+//! listener of their own. As the metrics are not meant for the public, the
+//! listener may only be bound to a loopback address. Requests are counted once
+//! their record has been written, by vhost and status, while requests for
+//! unknown hosts are counted under the vhost `-` so that clients can't create
+//! new series at will.
+
+use {
+    crate::{
+        config::{Address, Level},
+        log::{self, LogLevel, Record},
+        threadpool, CONFIG,
+    },
+    std::{
+        collections::BTreeMap,
+        fmt::Write as _,
+        io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+        net::{IpAddr, TcpListener, TcpStream},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Mutex, PoisonError,
+        },
+        thread,
+        time::Duration,
+    },
+};
+
+/// The upper bounds, in seconds, of the buckets of the duration histograms
+const BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0,
+];
+
+/// The vhost under which requests for unknown hosts are counted
+const UNKNOWN: &str = "-";
+
+/// How long a client may take to send it's request
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most a client may send as it's request
+const MAX_REQUEST: u64 = 8192;
+
+/// The phases of a request, in the order in which they are stored
+const PHASES: [&str; 3] = ["parse", "handle", "write"];
+
+/// The metrics gathered from the records of requests
+static STATE: Mutex<State> = Mutex::new(State::new());
+
+/// The number of CGI programs run
+static CGI_EXECUTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// The number of CGI programs which failed, including those which timed out
+static CGI_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// The number of CGI programs killed for running too long
+static CGI_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+/// The number of connections refused before a request was read
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+
+/// A histogram of durations, with cumulative buckets
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [0; BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bucket, le) in self.buckets.iter_mut().zip(BUCKETS) {
+            if secs <= le {
+                *bucket += 1;
+            }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    /// Writes the series of the histogram, with `labels` added to each
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        for (n, le) in self.buckets.iter().zip(BUCKETS) {
+            _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {n}");
+        }
+        _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count);
+        _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+struct State {
+    /// Requests by vhost and status
+    requests: BTreeMap<(String, u8), u64>,
+    /// Bytes sent by vhost
+    bytes: BTreeMap<String, u64>,
+    /// The duration of requests by vhost
+    durations: BTreeMap<String, Histogram>,
+    /// The duration of each phase of every request
+    phases: [Histogram; PHASES.len()],
+}
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            requests: BTreeMap::new(),
+            bytes: BTreeMap::new(),
+            durations: BTreeMap::new(),
+            phases: [Histogram::new(), Histogram::new(), Histogram::new()],
+        }
+    }
+}
+
+/// Counts a request once it has been handled
+pub fn observe(record: &Record) {
+    if CONFIG.metrics.is_none() {
+        return;
+    }
+    let vhost = record
+        .vhost
+        .as_deref()
+        .and_then(|host| CONFIG.named_vhost(host))
+        .map_or(UNKNOWN, |server| server.name.as_str());
+    let mut state = STATE.lock().unwrap_or_else(PoisonError::into_inner);
+    *state
+        .requests
+        .entry((vhost.to_string(), record.status))
+        .or_default() += 1;
+    *state.bytes.entry(vhost.to_string()).or_default() += record.bytes as u64;
+    state
+        .durations
+        .entry(vhost.to_string())
+        .or_insert_with(Histogram::new)
+        .observe(record.duration);
+    let phases = [record.parse, record.handle, record.write];
+    for (histogram, duration) in state.phases.iter_mut().zip(phases) {
+        histogram.observe(duration);
+    }
+}
+
+/// Counts a CGI program being run
+pub fn cgi_executed() {
+    CGI_EXECUTIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a CGI program which failed or was killed for running too long
+pub fn cgi_failed(timed_out: bool) {
+    CGI_FAILURES.fetch_add(1, Ordering::Relaxed);
+    if timed_out {
+        CGI_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a connection refused before a request was read
+pub fn connection_rejected() {
+    REJECTED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Escapes a label value
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Writes the help and type lines of a metric
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    _ = writeln!(out, "# HELP {name} {help}");
+    _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Renders every metric in the Prometheus text format
+#[must_use]
+pub fn render() -> String {
+    let mut out = String::new();
+    {
+        let state = STATE.lock().unwrap_or_else(PoisonError::into_inner);
+        let name = "agis_requests_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Requests handled by vhost and status",
+        );
+        for ((vhost, status), n) in &state.requests {
+            let vhost = label(vhost);
+            _ = writeln!(out, "{name}{{vhost=\"{vhost}\",status=\"{status}\"}} {n}");
+        }
+        let name = "agis_response_bytes_total";
+        header(&mut out, name, "counter", "Bytes sent to clients by vhost");
+        for (vhost, n) in &state.bytes {
+            _ = writeln!(out, "{name}{{vhost=\"{}\"}} {n}", label(vhost));
+        }
+        let name = "agis_request_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "How long requests took by vhost",
+        );
+        for (vhost, histogram) in &state.durations {
+            histogram.write(&mut out, name, &format!("vhost=\"{}\"", label(vhost)));
+        }
+        let name = "agis_request_phase_duration_seconds";
+        header(&mut out, name, "histogram", "How long each phase took");
+        for (phase, histogram) in PHASES.iter().zip(&state.phases) {
+            histogram.write(&mut out, name, &format!("phase=\"{phase}\""));
+        }
+    }
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let metrics = [
+        (
+            "agis_cgi_executions_total",
+            "counter",
+            "CGI programs run",
+            load(&CGI_EXECUTIONS),
+        ),
+        (
+            "agis_cgi_failures_total",
+            "counter",
+            "CGI programs which failed",
+            load(&CGI_FAILURES),
+        ),
+        (
+            "agis_cgi_timeouts_total",
+            "counter",
+            "CGI programs killed",
+            load(&CGI_TIMEOUTS),
+        ),
+        (
+            "agis_rejected_connections_total",
+            "counter",
+            "Connections refused",
+            load(&REJECTED),
+        ),
+        (
+            "agis_log_lines_dropped_total",
+            "counter",
+            "Log lines dropped",
+            log::queue::dropped(),
+        ),
+        (
+            "agis_threadpool_queued",
+            "gauge",
+            "Connections waiting for a worker",
+            threadpool::queued() as u64,
+        ),
+        (
+            "agis_threadpool_busy_workers",
+            "gauge",
+            "Workers handling a connection",
+            threadpool::busy() as u64,
+        ),
+        (
+            "agis_threadpool_workers",
+            "gauge",
+            "Workers in the pool",
+            CONFIG.threads as u64,
+        ),
+    ];
+    for (name, kind, help, value) in metrics {
+        header(&mut out, name, kind, help);
+        _ = writeln!(out, "{name} {value}");
+    }
+    out
+}
+
+/// Answers a single scrape. Only `GET /metrics` is served.
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers are read, but not used, so that the client has sent
+    // everything before the connection is closed
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next().map(|p| p.split('?').next())) {
+        (Some("GET"), Some(Some("/metrics"))) => ("200 OK", render()),
+        (Some("GET"), _) => ("404 Not Found", String::from("Not found\n")),
+        _ => ("400 Bad Request", String::from("Bad request\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.0 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// Binds the metrics listener, which must be on a loopback address
+/// # Errors
+/// Returns an `io::Error` if the address is not a loopback address, or if
+/// unable to bind to it
+pub fn bind(address: &Address) -> io::Result<TcpListener> {
+    let ip = address
+        .ip
+        .parse::<IpAddr>()
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("{}: {e}", address.ip)))?;
+    if !ip.is_loopback() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Metrics must be served on a loopback address, not {ip}"),
+        ));
+    }
+    let listener = TcpListener::bind(format!("{}:{}", address.ip, address.port))?;
+    let msg = format!(
+        "Binding metrics listener to address {} on port {}",
+        address.ip, address.port
+    );
+    if let Err(e) = msg.log_at(Level::Info) {
+        eprintln!("{e}");
+    }
+    Ok(listener)
+}
+
+/// Answers scrapes on a separate thread, one at a time
+/// # Errors
+/// Returns an `io::Error` if unable to start the thread
+pub fn serve(listener: TcpListener) -> io::Result<()> {
+    thread::Builder::new()
+        .name(String::from("metrics"))
+        .spawn(move || {
+            for stream in listener.incoming() {
+                if let Err(e) = stream.and_then(respond) {
+                    let msg = format!("Metrics: {e}");
+                    if let Err(e) = msg.log_at(Level::Warn) {
+                        eprintln!("{e}");
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
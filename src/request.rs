@@ -1,10 +1,16 @@
 use {
-    crate::error::RequestError,
+    crate::{
+        config::{Directive, Rewrite},
+        error::RequestError,
+        log::anonymize,
+        CONFIG,
+    },
     std::{
         convert::TryFrom,
         fmt,
-        io::{BufRead, BufReader, Read},
+        io::{self, BufRead, BufReader, ErrorKind, Read},
         net::{IpAddr, TcpStream},
+        path::Path,
     },
 };
 
@@ -25,6 +31,8 @@ pub struct Request {
     pub content: Option<Vec<u8>>,
 }
 
+/// Describes the request for the error log. The query string is only noted as
+/// present, as it may carry write tokens or other secrets.
 impl fmt::Display for Request {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -32,15 +40,65 @@ impl fmt::Display for Request {
             "Request: {{ host: {}; path: {}; query: {}; client_ip: {}; length: {}; }}",
             &self.host,
             &self.path,
-            self.query
-                .as_ref()
-                .map_or("none", std::string::String::as_str),
-            self.client_ip,
+            if self.query.is_some() {
+                "<redacted>"
+            } else {
+                "none"
+            },
+            anonymize::client_ip(self.client_ip),
             self.length,
         )
     }
 }
 
+/// The most content any request may carry
+const MAX_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Checks the length of the body sent to `path` before it is read, so that a
+/// body which would be refused is never read into memory. An upload may be as
+/// large as the `max_size` of the Upload directive it falls under, while CGI
+/// programs, and paths which are rewritten or aliased elsewhere, may be sent
+/// up to `MAX_LENGTH`. Static files accept no body at all.
+fn check_length(host: &str, path: &str, length: usize) -> Result<(), RequestError> {
+    if length == 0 {
+        return Ok(());
+    }
+    let Some(server) = CONFIG.vhost(host) else {
+        return Err(RequestError::BodyNotAccepted);
+    };
+    let directives: Vec<&Directive> = server
+        .directories
+        .iter()
+        .filter(|(dir, _)| Path::new(path).starts_with(dir))
+        .map(|(_, directive)| directive)
+        .collect();
+    let upload = directives
+        .iter()
+        .filter_map(|directive| match directive {
+            Directive::Upload { max_size, .. } => Some(*max_size),
+            _ => None,
+        })
+        .min();
+    let max = if let Some(max_size) = upload {
+        max_size.min(MAX_LENGTH)
+    } else if Rewrite::first_match(&server.rewrites, path).is_some()
+        || directives.iter().any(|directive| {
+            matches!(
+                directive,
+                Directive::Cgi | Directive::ScriptAlias(_) | Directive::Alias(_)
+            )
+        })
+    {
+        MAX_LENGTH
+    } else {
+        return Err(RequestError::BodyNotAccepted);
+    };
+    if length > max {
+        return Err(RequestError::TooLarge);
+    }
+    Ok(())
+}
+
 impl TryFrom<&TcpStream> for Request {
     type Error = RequestError;
 
@@ -57,14 +115,6 @@ impl TryFrom<&TcpStream> for Request {
                     Ok(l) => l,
                     Err(_) => return Err(RequestError::InvalidContentLength),
                 };
-                let content = match length {
-                    0 => None,
-                    length => {
-                        let mut buf = vec![0; length];
-                        reader.read_exact(&mut buf)?;
-                        Some(buf)
-                    }
-                };
                 let url = urlencoding::decode(parts[1])?;
                 let (mut path, query) = if let Some((p, q)) = url.split_once('?') {
                     (p.to_string(), Some(q.to_string()))
@@ -74,6 +124,20 @@ impl TryFrom<&TcpStream> for Request {
                 if path.is_empty() {
                     path.push('/');
                 }
+                check_length(parts[0], &path, length)?;
+                let content = match length {
+                    0 => None,
+                    length => {
+                        // The buffer grows as the body arrives, rather than
+                        // trusting the client to send as much as it claims
+                        let mut buf = Vec::new();
+                        (&mut reader).take(length as u64).read_to_end(&mut buf)?;
+                        if buf.len() < length {
+                            return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+                        }
+                        Some(buf)
+                    }
+                };
                 let client_ip = stream.peer_addr()?.ip();
                 Ok(Self {
                     host: parts[0].to_string(),
@@ -88,3 +152,40 @@ impl TryFrom<&TcpStream> for Request {
         }
     }
 }
+
+/// Resolves a link target relative to the path of the document which
+/// contains it, removing any `.` and `..` components
+#[must_use]
+pub fn resolve(base: &str, target: &str) -> String {
+    let (target, query) = match target.split_once('?') {
+        Some((t, q)) => (t, Some(q)),
+        None => (target, None),
+    };
+    let joined = if target.starts_with('/') {
+        target.to_string()
+    } else {
+        let dir = base.rfind('/').map_or("/", |idx| &base[..=idx]);
+        format!("{dir}{target}")
+    };
+    let mut parts = vec![];
+    for part in joined.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                _ = parts.pop();
+            }
+            p => parts.push(p),
+        }
+    }
+    let mut path = format!("/{}", parts.join("/"));
+    if matches!(joined.rsplit('/').next(), Some("" | "." | ".."))
+        && !path.ends_with('/')
+    {
+        path.push('/');
+    }
+    if let Some(q) = query {
+        path.push('?');
+        path.push_str(q);
+    }
+    path
+}
@@ -3,6 +3,8 @@
 //! and the addition of the ability to handle a request body saved to a temporary
 //! file. The CGI environment variables which are passed to the program are as
 //! follows:
+//! - `CONTENT_LENGTH` is the length in bytes of the request body, or `0` if the
+//!   request did not carry a body.
 //! - `DOCUMENT_ROOT` is the document root of the virtual host serving this request.
 //! - `QUERY_STRING` is the portion of the request following the '?' character,
 //!   useful for setting additional variables.
@@ -18,21 +20,38 @@
 //!   body. This variable will be an empty string if there was no request body.
 //!   The file that it points to may contain any arbitrary data and should as
 //!   such be treated as untrusted input.
+//!
+//! Spartan clients send any user input, such as the response to a `=:` prompt
+//! line, as the request body rather than in the query string. By convention, a
+//! CGI program should therefore read user input from the file at `REQUEST_BODY`
+//! whenever `CONTENT_LENGTH` is greater than zero, and treat `QUERY_STRING` only
+//! as parameters which were embedded in the link by the author. Input from a
+//! Gopher search is passed in the request body as well.
 
 use super::Response;
 use {
     super::Request,
-    crate::{config::Server, response::ServerError, CONFIG},
+    crate::{
+        config::Server,
+        log::{anonymize, LogError},
+        metrics,
+        response::ServerError,
+        CONFIG,
+    },
     std::{
         fs::File,
-        io::{self, Write},
+        io::{self, ErrorKind, Read, Write},
         path::{Path, PathBuf},
-        process::{Command, Output},
+        process::{Command, Output, Stdio},
+        thread,
+        time::Duration,
     },
+    wait_timeout::ChildExt,
 };
 
 /// The data to be passed into the CGI environment
 pub struct Cgi {
+    content_length: String,
     document_root: String,
     query_string: String,
     remote_addr: String,
@@ -69,9 +88,10 @@ impl Cgi {
         };
         let server_software = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
         Ok(Self {
+            content_length: request.length.to_string(),
             document_root: format!("{}", server.root.display()),
             query_string,
-            remote_addr: format!("{}", request.client_ip),
+            remote_addr: anonymize::client_ip(request.client_ip),
             request_uri: format!(
                 "{}{}",
                 &request.path,
@@ -111,9 +131,10 @@ impl Cgi {
         };
         let server_software = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
         Ok(Self {
+            content_length: request.length.to_string(),
             document_root: format!("{}", server.root.display()),
             query_string,
-            remote_addr: format!("{}", request.client_ip),
+            remote_addr: anonymize::client_ip(request.client_ip),
             request_uri: match request.query {
                 Some(q) => format!("{}?{q}", &request.path),
                 None => request.path.to_string(),
@@ -143,10 +164,11 @@ impl Cgi {
             }
             None => String::new(),
         };
-        Command::new(&self.script_filename)
+        let mut child = Command::new(&self.script_filename)
             .env_clear()
             .envs([
                 ("PATH", "/usr/local/bin:/usr/bin:/bin"),
+                ("CONTENT_LENGTH", &self.content_length),
                 ("DOCUMENT_ROOT", &self.document_root),
                 ("QUERY_STRING", &self.query_string),
                 ("REMOTE_ADDR", &self.remote_addr),
@@ -158,17 +180,53 @@ impl Cgi {
                 ("SERVER_SOFTWARE", &self.server_software),
                 ("REQUEST_BODY", &tmpfile),
             ])
-            .output()
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        // The output is read on another thread so that a program filling the
+        // pipe can't block while we wait for it to exit
+        let mut stdout = child.stdout.take().ok_or(ErrorKind::BrokenPipe)?;
+        let reader = thread::spawn(move || {
+            let mut buf = vec![];
+            stdout.read_to_end(&mut buf).map(|_| buf)
+        });
+        let status = match CONFIG.cgi_timeout {
+            Some(secs) => {
+                let Some(status) = child.wait_timeout(Duration::from_secs(secs))? else {
+                    _ = child.kill();
+                    _ = child.wait();
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        format!("killed after {secs} seconds"),
+                    ));
+                };
+                status
+            }
+            None => child.wait()?,
+        };
+        let stdout = reader
+            .join()
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))??;
+        Ok(Output {
+            status,
+            stdout,
+            stderr: vec![],
+        })
     }
 }
 
 impl From<Cgi> for Response {
     fn from(cgi: Cgi) -> Self {
+        metrics::cgi_executed();
         match cgi.run() {
             Ok(output) => {
-                let idx = match output.stdout.iter().position(|&x| x == b'\n') {
-                    Some(i) => i,
-                    None => return ServerError::CgiError.into(),
+                let idx = output.stdout.iter().position(|&x| x == b'\n');
+                if idx.is_none() || !output.status.success() {
+                    metrics::cgi_failed(false);
+                }
+                let Some(idx) = idx else {
+                    return ServerError::CgiError.into();
                 };
                 let mimetype = String::from_utf8_lossy(&output.stdout[0..idx]);
                 let body = Vec::from(&output.stdout[idx + 1..]);
@@ -177,7 +235,18 @@ impl From<Cgi> for Response {
                     body,
                 }
             }
-            Err(_) => ServerError::CgiError.into(),
+            Err(e) => {
+                metrics::cgi_failed(e.kind() == ErrorKind::TimedOut);
+                let msg = format!("CGI program {} failed: {e}", cgi.script_filename);
+                if let Err(e) = msg.log_err() {
+                    eprintln!("{e}");
+                }
+                match e.kind() {
+                    ErrorKind::TimedOut => ServerError::Timeout.into(),
+                    ErrorKind::WouldBlock | ErrorKind::ResourceBusy => ServerError::Busy.into(),
+                    _ => ServerError::CgiError.into(),
+                }
+            }
         }
     }
 }
@@ -0,0 +1,60 @@
+//! Replaces errors with the responses configured for them by a virtual host.
+//! The details of an error are only ever written to the error log, while the
+//! client receives either a generic message or the configured response.
+
+use {
+    super::{mime, Response},
+    crate::{
+        config::{ErrorPage, Server},
+        log::LogError,
+        CONFIG,
+    },
+    std::fs,
+};
+
+impl Response {
+    /// Replaces an error with the response configured for it's kind by the
+    /// virtual host, if any
+    #[must_use]
+    pub fn error_page(self, server: &Server) -> Self {
+        let status = self.status();
+        let kind = match &self {
+            Self::ClientError(e) => e.kind(),
+            Self::ServerError(e) => e.kind(),
+            _ => return self,
+        };
+        let Some(page) = server.errors.get(&kind) else {
+            return self;
+        };
+        match page {
+            ErrorPage::Message(message) => Self::CustomError {
+                status,
+                message: message.clone(),
+            },
+            ErrorPage::Redirect(target) => Self::Redirect {
+                target: target.clone(),
+                permanent: false,
+            },
+            ErrorPage::Page(path) => {
+                let file = server.root.join(path.strip_prefix("/").unwrap_or(path));
+                match fs::read(&file) {
+                    Ok(body) => {
+                        let mimetype = mime::with_params(
+                            mime::mimetype(&file, &body, server),
+                            server.charset.as_deref().or(CONFIG.charset.as_deref()),
+                            server.lang.as_deref().or(CONFIG.lang.as_deref()),
+                        );
+                        Self::Success { mimetype, body }
+                    }
+                    Err(e) => {
+                        let msg = format!("Unable to read error page {}: {e}", file.display());
+                        if let Err(e) = msg.log_err() {
+                            eprintln!("{e}");
+                        }
+                        self
+                    }
+                }
+            }
+        }
+    }
+}
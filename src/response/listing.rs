@@ -0,0 +1,116 @@
+//! Generates gemtext listings for directories which have no index file.
+//! Directories are listed first with a trailing `/`, followed by files, and
+//! link targets are percent encoded so that names containing spaces or other
+//! reserved characters work. If the directory contains the configured header
+//! or footer file, it's contents are placed above or below the listing.
+
+use {
+    super::{mime, Response},
+    crate::config::{Listing, SortBy},
+    chrono::{DateTime, Utc},
+    std::{cmp::Reverse, fmt::Write, fs, path::Path, time::SystemTime},
+};
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Formats a size in bytes using binary prefixes, eg. `1.5 KiB`
+#[allow(clippy::cast_precision_loss)]
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for u in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = u;
+    }
+    format!("{size:.1} {unit}")
+}
+
+impl Response {
+    /// Generates a listing of the contents of `dir`
+    #[must_use]
+    pub fn listing(dir: &Path, opts: &Listing) -> Self {
+        let contents = match fs::read_dir(dir) {
+            Ok(c) => c,
+            Err(e) => return Self::ServerError(e.into()),
+        };
+        let mut entries = vec![];
+        for entry in contents {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => return Self::ServerError(e.into()),
+            };
+            // Names which are not valid utf8 can not be linked to reliably
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if (name.starts_with('.') && !opts.show_hidden)
+                || name == opts.header
+                || name == opts.footer
+                || name == mime::META_FILE
+            {
+                continue;
+            }
+            let Ok(meta) = fs::metadata(entry.path()) else {
+                continue;
+            };
+            entries.push(Entry {
+                name,
+                is_dir: meta.is_dir(),
+                size: meta.len(),
+                modified: meta.modified().ok(),
+            });
+        }
+        match opts.sort {
+            SortBy::Name => entries.sort_by(|a, b| a.name.cmp(&b.name)),
+            SortBy::Modified => entries.sort_by_key(|e| Reverse(e.modified)),
+        }
+        // A stable sort keeps the order within directories and files
+        entries.sort_by_key(|e| !e.is_dir);
+        let mut body = match fs::read_to_string(dir.join(&opts.header)) {
+            Ok(header) => header,
+            Err(_) => String::from("# Directory listing\n"),
+        };
+        if !body.is_empty() && !body.ends_with('\n') {
+            body.push('\n');
+        }
+        body.push_str("=> ../ Parent\n");
+        for entry in &entries {
+            let target = urlencoding::encode(&entry.name);
+            let slash = if entry.is_dir { "/" } else { "" };
+            _ = write!(body, "=> {target}{slash} {}{slash}", entry.name);
+            if opts.details {
+                let modified = entry.modified.map(|m| {
+                    DateTime::<Utc>::from(m)
+                        .format("%Y-%m-%d %H:%M")
+                        .to_string()
+                });
+                match (entry.is_dir, modified) {
+                    (true, Some(m)) => _ = write!(body, " ({m})"),
+                    (false, Some(m)) => _ = write!(body, " ({}, {m})", human_size(entry.size)),
+                    (false, None) => _ = write!(body, " ({})", human_size(entry.size)),
+                    (true, None) => {}
+                }
+            }
+            body.push('\n');
+        }
+        if let Ok(footer) = fs::read_to_string(dir.join(&opts.footer)) {
+            body.push_str(&footer);
+        }
+        Self::Success {
+            mimetype: String::from("text/gemini"),
+            body: body.into_bytes(),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Determines the mimetype of static files. The extension of the file is
+//! looked up in the table of the virtual host, then in the global table and
+//! finally in a built in table of common types. Only files with an unknown
+//! extension have their contents examined.
+//!
+//! The mimetype of individual files may be overridden by a `.meta` sidecar
+//! file in the same directory or any directory above it, up to the server
+//! root. Each line holds a pattern and a value separated by a colon:
+//!
+//! ```text
+//! # Comments and blank lines are ignored
+//! *.gmi: ;lang=de
+//! notes.txt: text/markdown; charset=iso-8859-1
+//! drafts/*.gmi: ;lang=en;charset=utf-8
+//! ```
+//!
+//! Patterns are matched against the path of the file relative to the
+//! directory holding the `.meta` file, where `*` matches any run of
+//! characters other than `/` and `?` matches any one such character. A value
+//! beginning with `;` holds parameters added to the mimetype, while any other
+//! value replaces the mimetype entirely. The nearest `.meta` file containing a
+//! matching pattern is used, and within it the first matching line.
+
+use {
+    crate::{config::Server, CONFIG},
+    std::{ffi::OsStr, fs, path::Path},
+};
+
+/// The name of sidecar metadata files, which are never served
+pub const META_FILE: &str = ".meta";
+
+/// Metadata for a file, taken from a sidecar file
+pub enum Meta {
+    /// Replaces the mimetype of the file
+    Mimetype(String),
+    /// Parameters added to the mimetype of the file, eg. `lang=de;charset=utf-8`
+    Params(String),
+}
+
+/// Mimetypes for common file extensions
+const BUILTIN: &[(&str, &str)] = &[
+    ("gmi", "text/gemini"),
+    ("gemini", "text/gemini"),
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("markdown", "text/markdown"),
+    ("csv", "text/csv"),
+    ("css", "text/css"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("xml", "text/xml"),
+    ("atom", "application/atom+xml"),
+    ("rss", "application/rss+xml"),
+    ("json", "application/json"),
+    ("js", "text/javascript"),
+    ("pdf", "application/pdf"),
+    ("epub", "application/epub+zip"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("opus", "audio/opus"),
+    ("flac", "audio/flac"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// Looks up the mimetype for the extension of `path`
+#[must_use]
+pub fn from_extension(path: &Path, server: &Server) -> Option<String> {
+    let ext = path.extension().and_then(OsStr::to_str)?.to_lowercase();
+    server
+        .mimetypes
+        .get(&ext)
+        .or_else(|| CONFIG.mimetypes.get(&ext))
+        .cloned()
+        .or_else(|| {
+            BUILTIN
+                .iter()
+                .find(|(e, _)| *e == ext)
+                .map(|(_, m)| (*m).to_string())
+        })
+}
+
+/// Determines the mimetype of a file from it's extension, falling back to
+/// examining the contents if the extension is unknown
+#[must_use]
+pub fn mimetype(path: &Path, body: &[u8], server: &Server) -> String {
+    from_extension(path, server).unwrap_or_else(|| tree_magic_mini::from_u8(body).to_string())
+}
+
+/// Whether a mimetype already carries the named parameter
+fn has_param(mimetype: &str, name: &str) -> bool {
+    mimetype.split(';').skip(1).any(|p| {
+        p.split_once('=')
+            .is_some_and(|(k, _)| k.trim().eq_ignore_ascii_case(name))
+    })
+}
+
+/// Appends each of the `;` separated parameters in `params` which the
+/// mimetype does not already carry
+#[must_use]
+pub fn append(mut mimetype: String, params: &str) -> String {
+    for param in params.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+        let name = param.split_once('=').map_or(param, |(k, _)| k.trim());
+        if !has_param(&mimetype, name) {
+            mimetype.push_str("; ");
+            mimetype.push_str(param);
+        }
+    }
+    mimetype
+}
+
+/// Matches a path against a pattern, where `*` matches any run of characters
+/// other than `/` and `?` matches any one such character
+fn glob(pattern: &[u8], path: &[u8]) -> bool {
+    match (pattern.split_first(), path.split_first()) {
+        (None, _) => path.is_empty(),
+        (Some((b'*', rest)), _) => {
+            glob(rest, path)
+                || matches!(path.split_first(), Some((c, p)) if *c != b'/' && glob(pattern, p))
+        }
+        (Some((b'?', rest)), Some((c, p))) => *c != b'/' && glob(rest, p),
+        (Some((a, rest)), Some((b, p))) => a == b && glob(rest, p),
+        (Some(_), None) => false,
+    }
+}
+
+/// Finds the value for `name` in the contents of a sidecar file
+fn lookup(contents: &str, name: &str) -> Option<Meta> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| l.split_once(':'))
+        .find(|(pattern, _)| glob(pattern.trim().as_bytes(), name.as_bytes()))
+        .and_then(|(_, value)| match value.trim() {
+            "" => None,
+            v => Some(match v.strip_prefix(';') {
+                Some(params) => Meta::Params(params.to_string()),
+                None => Meta::Mimetype(v.to_string()),
+            }),
+        })
+}
+
+/// Searches the sidecar files from the directory containing `path` upward
+/// to `root` for metadata applying to `path`
+#[must_use]
+pub fn sidecar(path: &Path, root: &Path) -> Option<Meta> {
+    let mut dir = path.parent()?;
+    loop {
+        if let Ok(contents) = fs::read_to_string(dir.join(META_FILE)) {
+            let name = path.strip_prefix(dir).ok()?.to_str()?;
+            if let Some(meta) = lookup(&contents, name) {
+                return Some(meta);
+            }
+        }
+        if dir == root {
+            return None;
+        }
+        dir = dir.parent().filter(|d| d.starts_with(root))?;
+    }
+}
+
+/// Appends `charset` to text types and `lang` to gemtext, unless the mimetype
+/// already carries that parameter
+#[must_use]
+pub fn with_params(mut mimetype: String, charset: Option<&str>, lang: Option<&str>) -> String {
+    if let Some(charset) = charset {
+        if mimetype.starts_with("text/") && !has_param(&mimetype, "charset") {
+            mimetype.push_str("; charset=");
+            mimetype.push_str(charset);
+        }
+    }
+    if let Some(lang) = lang {
+        if mimetype.starts_with("text/gemini") && !has_param(&mimetype, "lang") {
+            mimetype.push_str("; lang=");
+            mimetype.push_str(lang);
+        }
+    }
+    mimetype
+}
@@ -1,31 +1,50 @@
 pub mod cgi;
+pub mod errors;
+pub mod listing;
+pub mod mime;
+pub mod prompt;
+pub mod token;
+pub mod upload;
 
 use {
     crate::{
-        config::Directive,
+        config::{AccessRule, Directive, Level, Listing, Rewrite, Server},
         error::{RequestError, ServerError},
+        log::{self, LogLevel},
         request::Request,
         CONFIG,
     },
     cgi::Cgi,
     std::{
-        fmt::{self, Write},
-        fs::{self, File},
+        fmt,
+        fs::File,
         io::{self, BufReader, ErrorKind, Read},
-        path::PathBuf,
+        path::{Path, PathBuf},
     },
 };
 
+/// Logs how a request is being handled, only formatting the message if
+/// messages at `level` are logged
+fn trace(level: Level, msg: impl FnOnce() -> String) {
+    if log::enabled(level) {
+        if let Err(e) = msg().log_at(level) {
+            eprintln!("{e}");
+        }
+    }
+}
+
 /// Represents the response which will be sent back to the client
 pub enum Response {
     /// The resource is valid and will be served
     Success { mimetype: String, body: Vec<u8> },
     /// The client is directed to resubmit the request with a different Url path
-    Redirect(PathBuf),
+    Redirect { target: String, permanent: bool },
     /// The client sent a non-conforming request
     ClientError(RequestError),
     /// The server encountered an error processing a valid request
     ServerError(ServerError),
+    /// An error whose message has been configured by the virtual host
+    CustomError { status: u8, message: String },
 }
 
 impl From<ServerError> for Response {
@@ -46,9 +65,31 @@ impl fmt::Display for Response {
             Self::Success { mimetype, body: _ } => {
                 write!(f, "Response::Success({mimetype})")
             }
-            Self::Redirect(path) => write!(f, "Response::Redirect({})", path.display()),
+            Self::Redirect { target, permanent } => {
+                let kind = if *permanent { "permanent" } else { "temporary" };
+                write!(f, "Response::Redirect({kind} {target})")
+            }
             Self::ClientError(e) => write!(f, "Response::ClientError({e})"),
             Self::ServerError(e) => write!(f, "Response::ServerError({e})"),
+            Self::CustomError { status, message } => {
+                write!(f, "Response::CustomError({status} {message})")
+            }
+        }
+    }
+}
+
+impl Response {
+    /// The Spartan status of this response. Client errors are always sent
+    /// with status 4, while server errors may be sent with either 4 or 5, see
+    /// `ServerError::status`.
+    #[must_use]
+    pub fn status(&self) -> u8 {
+        match self {
+            Self::Success { .. } => 2,
+            Self::Redirect { .. } => 3,
+            Self::ClientError(_) => 4,
+            Self::ServerError(e) => e.status(),
+            Self::CustomError { status, .. } => *status,
         }
     }
 }
@@ -61,75 +102,246 @@ impl From<Response> for Vec<u8> {
                 buf.append(&mut body);
                 buf
             }
-            Response::Redirect(path) => format!("3 {}\r\n", path.display()).into_bytes(),
-            Response::ClientError(e) => format!("4 {e}\r\n").into_bytes(),
-            Response::ServerError(e) => format!("5 {e}\r\n").into_bytes(),
+            Response::Redirect { target, .. } => format!("3 {target}\r\n").into_bytes(),
+            Response::ClientError(e) => format!("4 {}\r\n", e.message()).into_bytes(),
+            Response::ServerError(e) => format!("{} {}\r\n", e.status(), e.message()).into_bytes(),
+            Response::CustomError { status, message } => {
+                format!("{status} {message}\r\n").into_bytes()
+            }
         }
     }
 }
 
 impl From<PathBuf> for Response {
     fn from(dir: PathBuf) -> Response {
-        let contents = match fs::read_dir(dir) {
-            Ok(c) => c,
-            Err(e) => return Self::ServerError(e.into()),
-        };
-        let mut body = String::from("# Directory listing\n=> .. Parent\n");
-        for entry in contents {
-            let entry = match entry {
-                Ok(e) => e,
-                Err(e) => return Self::ServerError(e.into()),
-            };
-            let entry = if let Some(e) = entry.file_name().to_str() {
-                e.to_string()
-            } else {
-                let err = io::Error::new(ErrorKind::Other, "Invalid pathname");
-                return Self::ServerError(err.into());
-            };
-            if let Err(e) = writeln!(body, "=> {entry}") {
+        Self::listing(&dir, &Listing::default())
+    }
+}
+
+/// The number of times a request may be rewritten or aliased before it is
+/// assumed to be caught in a loop
+const MAX_REWRITES: usize = 10;
+
+/// Settings gathered from the directives matching a request, which affect how
+/// a static file or directory is served
+struct Settings<'a> {
+    /// Whether to check the input prompts in gemtext documents
+    validate_prompts: bool,
+    /// The files to look for, in order, when a directory is requested
+    index: &'a [String],
+    /// Whether to generate a listing for directories without an index file
+    autoindex: bool,
+    /// The language of gemtext files
+    lang: Option<&'a str>,
+    /// The charset of text files
+    charset: Option<&'a str>,
+}
+
+impl<'a> Settings<'a> {
+    /// The settings of a vhost before any directives are applied
+    fn new(server: &'a Server) -> Self {
+        Self {
+            validate_prompts: false,
+            index: &server.index,
+            autoindex: server.autoindex,
+            lang: server.lang.as_deref().or(CONFIG.lang.as_deref()),
+            charset: server.charset.as_deref().or(CONFIG.charset.as_deref()),
+        }
+    }
+}
+
+impl Response {
+    /// Serves the static file, index or directory listing which the request
+    /// path maps to under the server root
+    fn from_file(request: &Request, server: &Server, settings: &Settings) -> Self {
+        // A static file has no way to process any input sent in the request body
+        if request.content.is_some() {
+            return RequestError::BodyNotAccepted.into();
+        }
+        let mut path = server.root.clone();
+        let request_base = match PathBuf::from(&request.path).strip_prefix("/") {
+            Ok(p) => p.to_path_buf(),
+            Err(e) => {
                 let err = io::Error::new(ErrorKind::Other, e);
                 return Self::ServerError(err.into());
             }
+        };
+        path.push(request_base);
+        if path.is_dir() {
+            if !request.path.ends_with('/') {
+                let mut path = request.path.clone();
+                path.push('/');
+                return Self::Redirect {
+                    target: path,
+                    permanent: false,
+                };
+            }
+            match settings
+                .index
+                .iter()
+                .map(|name| path.join(name))
+                .find(|p| p.is_file())
+            {
+                Some(index) => path = index,
+                None if settings.autoindex => return Self::listing(&path, &server.listing),
+                None => return ServerError::Unauthorized.into(),
+            }
         }
-        Self::Success {
-            mimetype: String::from("text/gemini"),
-            body: body.into_bytes(),
+        if path.file_name().is_some_and(|n| n == mime::META_FILE) {
+            return ServerError::NotFound.into();
         }
+        let fd = match File::open(&path) {
+            Ok(f) => f,
+            Err(e) => return Self::ServerError(e.into()),
+        };
+        let mut reader = BufReader::new(fd);
+        let mut body = vec![];
+        if let Err(e) = reader.read_to_end(&mut body) {
+            return Self::ServerError(e.into());
+        }
+        let mut mimetype = mime::mimetype(&path, &body, server);
+        let mut params = None;
+        match mime::sidecar(&path, &server.root) {
+            Some(mime::Meta::Mimetype(m)) => mimetype = m,
+            Some(mime::Meta::Params(p)) => params = Some(p),
+            None => {}
+        }
+        if settings.validate_prompts && mimetype.starts_with("text/gemini") {
+            prompt::validate(&body, &request.path, server);
+        }
+        // Parameters from a sidecar file take precedence over the defaults
+        if let Some(params) = params {
+            mimetype = mime::append(mimetype, &params);
+        }
+        let mimetype = mime::with_params(mimetype, settings.charset, settings.lang);
+        trace(Level::Trace, || {
+            format!("Serving {} as {mimetype}", path.display())
+        });
+        Self::Success { mimetype, body }
     }
 }
 
 impl From<Request> for Response {
     fn from(request: Request) -> Self {
-        let server = match CONFIG.vhosts.get(&request.host) {
+        Self::route(request, 0)
+    }
+}
+
+impl Response {
+    /// Serves the path which a rewrite rule has rewritten the request path
+    /// into, or redirects the client to it
+    fn rewrite(request: Request, rule: &Rewrite, target: String, depth: usize) -> Self {
+        let (path, query) = match target.split_once('?') {
+            Some((p, q)) => (p.to_string(), Some(q.to_string())),
+            None => (target, request.query.clone()),
+        };
+        if rule.redirect {
+            let target = match query {
+                Some(q) => format!("{path}?{q}"),
+                None => path,
+            };
+            return Self::Redirect {
+                target,
+                permanent: rule.permanent,
+            };
+        }
+        let r = Request {
+            path,
+            query,
+            ..request
+        };
+        Self::route(r, depth + 1)
+    }
+
+    /// Redirects the client to the target of a Redirect directive, carrying
+    /// over the part of the request path following the directive's path and
+    /// the query string
+    fn redirect(request: &Request, dir: &Path, target: &str, permanent: bool) -> Self {
+        let dir = dir.to_string_lossy();
+        let rest = request
+            .path
+            .get(dir.trim_end_matches('/').len()..)
+            .unwrap_or_default();
+        let mut target = if rest.is_empty() {
+            target.to_string()
+        } else {
+            format!("{}{rest}", target.trim_end_matches('/'))
+        };
+        if let Some(ref q) = request.query {
+            if !target.contains('?') {
+                target.push('?');
+                target.push_str(q);
+            }
+        }
+        Self::Redirect { target, permanent }
+    }
+
+    /// Serves the path which an Alias directive maps the request path onto
+    fn alias(request: Request, dir: &Path, target: &str, depth: usize) -> Self {
+        // Unwrap should be fine here, as we've already determined that
+        // our path variable begins with dir
+        let children = PathBuf::from(&request.path)
+            .strip_prefix(dir)
+            .map(|x| x.strip_prefix("/").unwrap_or(x).to_path_buf())
+            .unwrap();
+        let mut path = PathBuf::from(target);
+        path.push(children);
+        let path = path.to_string_lossy().to_string();
+        let r = Request { path, ..request };
+        Self::route(r, depth + 1)
+    }
+
+    /// Formulates the response to a request which has been rewritten or
+    /// aliased `depth` times
+    fn route(request: Request, depth: usize) -> Self {
+        trace(Level::Trace, || {
+            format!("Routing {}{} at depth {depth}", request.host, request.path)
+        });
+        if depth > MAX_REWRITES {
+            return ServerError::RewriteLoop.into();
+        }
+        if AccessRule::evaluate(&CONFIG.access, request.client_ip) == Some(false) {
+            return ServerError::Unauthorized.into();
+        }
+        let server = match CONFIG.vhost(&request.host) {
             Some(s) => s,
             None => return ServerError::NotFound.into(),
         };
+        if let Some((rule, target)) = Rewrite::first_match(&server.rewrites, &request.path) {
+            trace(Level::Debug, || {
+                let path = &request.path;
+                format!("Rewrite {} matched {path}, giving {target}", rule.pattern)
+            });
+            return Self::rewrite(request, rule, target, depth);
+        }
+        let mut settings = Settings::new(server);
+        // The most specific Index and Meta directives apply
+        let mut index_dir: Option<&Path> = None;
+        let mut meta: Option<(&Path, Option<&str>, Option<&str>)> = None;
         for (dir, directive) in &server.directories {
             if PathBuf::from(&request.path).starts_with(dir) {
+                trace(Level::Debug, || {
+                    let (kind, dir) = (directive.kind(), dir.display());
+                    format!("Directive {kind} for {dir} matched {}", request.path)
+                });
                 match directive {
                     Directive::Allow(val) => {
                         if !val {
                             return ServerError::Unauthorized.into();
                         }
                     }
-                    Directive::Alias(path) => {
-                        // Unwrap should be fine here, as we've already determined that
-                        // our path variable begins with dir
-                        let children = PathBuf::from(&request.path)
-                            .strip_prefix(dir)
-                            .map(|x| x.strip_prefix("/").unwrap_or(x).to_path_buf())
-                            .unwrap();
-                        let mut path = PathBuf::from(path);
-                        path.push(children);
-                        let path = path.to_string_lossy().to_string();
-                        let r = Request { path, ..request };
-                        return Self::from(r);
-                    }
-                    Directive::Redirect(path) => {
-                        if PathBuf::from(&request.path).as_path() == dir.as_path() {
-                            return Self::Redirect(path.clone());
+                    Directive::Access(rules) => {
+                        if AccessRule::evaluate(rules, request.client_ip) == Some(false) {
+                            return ServerError::Unauthorized.into();
                         }
                     }
+                    Directive::Alias(path) => return Self::alias(request, dir, path, depth),
+                    Directive::Redirect(target) => {
+                        return Self::redirect(&request, dir, target, false);
+                    }
+                    Directive::PermanentRedirect(target) => {
+                        return Self::redirect(&request, dir, target, true);
+                    }
                     Directive::Interpreter(_prog) => {
                         unimplemented!();
                     }
@@ -147,44 +359,83 @@ impl From<Request> for Response {
                         };
                         return cgi.into();
                     }
+                    Directive::Index { files, autoindex } => {
+                        if index_dir.is_none_or(|d| dir.starts_with(d)) {
+                            index_dir = Some(dir);
+                            settings.index = files;
+                            settings.autoindex = *autoindex;
+                        }
+                    }
+                    Directive::Meta { lang, charset } => {
+                        if meta.is_none_or(|(d, _, _)| dir.starts_with(d)) {
+                            meta = Some((dir, lang.as_deref(), charset.as_deref()));
+                        }
+                    }
+                    Directive::Prompt => settings.validate_prompts = true,
+                    Directive::Gone => return ServerError::Gone.into(),
+                    Directive::Upload { .. } => {
+                        let upload = upload::Upload::from_directive(directive);
+                        if let Some(upload) = upload.filter(|u| u.is_write(&request)) {
+                            return upload.handle(request, server, dir);
+                        }
+                    }
                 }
             }
         }
-        let mut path = server.root.clone();
-        let request_base = match PathBuf::from(&request.path).strip_prefix("/") {
-            Ok(p) => p.to_path_buf(),
-            Err(e) => {
-                let err = io::Error::new(ErrorKind::Other, e);
-                return Self::ServerError(err.into());
-            }
-        };
-        path.push(request_base);
-        if path.is_dir() {
-            if !request.path.ends_with('/') {
-                let mut path = request.path.clone();
-                path.push('/');
-                return Self::Redirect(PathBuf::from(path));
-            }
-            path.push("index.gmi");
-            if !path.exists() {
-                _ = path.pop();
-                return path.into();
-            }
+        if let Some((_, lang, charset)) = meta {
+            settings.lang = lang.or(settings.lang);
+            settings.charset = charset.or(settings.charset);
         }
-        let fd = match File::open(&path) {
-            Ok(f) => f,
-            Err(e) => return Self::ServerError(e.into()),
+        Self::from_file(&request, server, &settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::net::{IpAddr, Ipv4Addr},
+    };
+
+    /// Where a Redirect directive for `dir` sends a request for `path`
+    fn redirect(path: &str, dir: &str, target: &str) -> String {
+        let (path, query) = match path.split_once('?') {
+            Some((p, q)) => (p, Some(q.to_string())),
+            None => (path, None),
         };
-        let mut reader = BufReader::new(fd);
-        let mut body = vec![];
-        if let Err(e) = reader.read_to_end(&mut body) {
-            return Self::ServerError(e.into());
-        }
-        let mimetype = match path.extension() {
-            Some(ext) if ext == "gmi" => "text/gemini",
-            _ => tree_magic_mini::from_u8(&body),
+        let request = Request {
+            host: String::from("localhost"),
+            path: path.to_string(),
+            query,
+            client_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            length: 0,
+            content: None,
+        };
+        match Response::redirect(&request, Path::new(dir), target, true) {
+            Response::Redirect { target, permanent } => {
+                assert!(permanent);
+                target
+            }
+            _ => panic!("not a redirect"),
         }
-        .to_string();
-        Self::Success { mimetype, body }
+    }
+
+    #[test]
+    fn redirect_remainder() {
+        assert_eq!(redirect("/old", "/old", "/new"), "/new");
+        assert_eq!(redirect("/old/", "/old", "/new"), "/new/");
+        assert_eq!(redirect("/old/a/b.gmi", "/old", "/new/"), "/new/a/b.gmi");
+        assert_eq!(redirect("/old/a", "/old/", "/new"), "/new/a");
+        assert_eq!(
+            redirect("/old/a", "/old", "spartan://example.com/"),
+            "spartan://example.com/a"
+        );
+    }
+
+    #[test]
+    fn redirect_query() {
+        assert_eq!(redirect("/old/a?x=1", "/old", "/new"), "/new/a?x=1");
+        // A target with a query of it's own keeps it
+        assert_eq!(redirect("/old?x=1", "/old", "/new?y=2"), "/new?y=2");
     }
 }
@@ -0,0 +1,53 @@
+//! Spartan input prompts are gemtext lines beginning with `=:`, followed by a
+//! url and an optional label. A client presents the label to the user, then
+//! sends whatever the user enters as the request body to that url. Since only
+//! CGI programs are able to do anything with a request body, a prompt pointing
+//! at any other local path can never succeed. This module checks the prompts
+//! in documents served from under a `Prompt` directive and logs any which are
+//! broken, so that they can be found without having to click on every one.
+
+use {
+    crate::{
+        config::{Level, Server},
+        log::LogLevel,
+        request,
+    },
+    std::path::Path,
+};
+
+/// Checks every prompt line in a gemtext document, logging any which are
+/// malformed or which point at a local path that does not accept input
+pub fn validate(body: &[u8], path: &str, server: &Server) {
+    let gemtext = String::from_utf8_lossy(body);
+    let mut preformatted = false;
+    for line in gemtext.lines() {
+        if line.starts_with("```") {
+            preformatted = !preformatted;
+            continue;
+        }
+        if preformatted {
+            continue;
+        }
+        let Some(prompt) = line.strip_prefix("=:") else {
+            continue;
+        };
+        let msg = match prompt.split_whitespace().next() {
+            None => format!("Malformed prompt in {}{path}: \"{line}\"", server.name),
+            Some(url) if url.contains("://") => continue,
+            Some(url) => {
+                let target = request::resolve(path, url);
+                let target = target.split_once('?').map_or(target.as_str(), |(p, _)| p);
+                if server.accepts_input(Path::new(target)) {
+                    continue;
+                }
+                format!(
+                    "Prompt in {}{path} points at {target}, which does not accept input",
+                    server.name
+                )
+            }
+        };
+        if let Err(e) = msg.log_at(Level::Warn) {
+            eprintln!("{e}");
+        }
+    }
+}
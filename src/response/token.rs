@@ -0,0 +1,147 @@
+//! Signed tokens granting write access to a path. Since Spartan has no means
+//! of authentication, a directive which accepts writes may be given a shared
+//! secret, in which case every write must carry a token in the query string:
+//!
+//! `/post/hello.gmi?expires=<unix time>&token=<hex>`
+//!
+//! The token is the hex encoded HMAC-SHA256, keyed with the secret, of the
+//! request path, the size of the request body in bytes and the expiry time,
+//! separated by newlines. Tokens are only valid for the exact path and size
+//! they were created for, and are refused once their expiry time has passed.
+
+use {
+    crate::sign::{self, HmacSha256},
+    hmac::Mac,
+    std::time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Gets the value of a parameter from a query string. A parameter which is
+/// present without a value gives an empty string.
+#[must_use]
+pub fn param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?
+        .split('&')
+        .find_map(|pair| match pair.split_once('=') {
+            Some((k, v)) if k == name => Some(v),
+            None if pair == name => Some(""),
+            _ => None,
+        })
+}
+
+fn mac(secret: &str, path: &str, size: usize, expires: u64) -> HmacSha256 {
+    let message = format!("{path}\n{size}\n{expires}");
+    sign::hmac(secret.as_bytes(), message.as_bytes())
+}
+
+/// Checks the token carried in the query string of a request
+/// # Errors
+/// Returns a description of the problem if the token is missing, malformed,
+/// expired or does not match
+pub fn verify(secret: &str, path: &str, query: Option<&str>, size: usize) -> Result<(), String> {
+    let Some(token) = param(query, "token") else {
+        return Err(String::from("missing token"));
+    };
+    let Some(expires) = param(query, "expires").and_then(|e| e.parse::<u64>().ok()) else {
+        return Err(String::from("missing or invalid expiry time"));
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    if expires < now {
+        return Err(format!("token expired at {expires}"));
+    }
+    if token.len() % 2 != 0 {
+        return Err(String::from("malformed token"));
+    }
+    let bytes: Option<Vec<u8>> = (0..token.len())
+        .step_by(2)
+        .map(|i| {
+            token
+                .get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+        })
+        .collect();
+    let Some(bytes) = bytes else {
+        return Err(String::from("malformed token"));
+    };
+    mac(secret, path, size, expires)
+        .verify_slice(&bytes)
+        .map_err(|_| String::from("signature mismatch"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "hunter2";
+
+    /// Signs a write of `size` bytes to `path`, as a client would
+    fn query(path: &str, size: usize, expires: u64) -> String {
+        let token = sign::hex(&mac(SECRET, path, size, expires).finalize().into_bytes());
+        format!("expires={expires}&token={token}")
+    }
+
+    fn future() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60
+    }
+
+    #[test]
+    fn valid() {
+        let query = query("/post/a.gmi", 5, future());
+        assert!(verify(SECRET, "/post/a.gmi", Some(&query), 5).is_ok());
+    }
+
+    #[test]
+    fn expired() {
+        let query = query("/post/a.gmi", 5, 1);
+        let err = verify(SECRET, "/post/a.gmi", Some(&query), 5).unwrap_err();
+        assert!(err.contains("expired"));
+    }
+
+    #[test]
+    fn bad_signature() {
+        let query = query("/post/a.gmi", 5, future());
+        assert!(verify("hunter3", "/post/a.gmi", Some(&query), 5).is_err());
+        let mut tampered = query.clone();
+        let last = tampered.pop().unwrap();
+        tampered.push(if last == '0' { '1' } else { '0' });
+        let err = verify(SECRET, "/post/a.gmi", Some(&tampered), 5).unwrap_err();
+        assert_eq!(err, "signature mismatch");
+    }
+
+    #[test]
+    fn wrong_path_or_size() {
+        let query = query("/post/a.gmi", 5, future());
+        assert!(verify(SECRET, "/post/b.gmi", Some(&query), 5).is_err());
+        assert!(verify(SECRET, "/post/a.gmi", Some(&query), 6).is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        let expires = future();
+        assert!(verify(SECRET, "/", None, 0).is_err());
+        assert!(verify(SECRET, "/", Some("token=00"), 0).is_err());
+        let odd = format!("expires={expires}&token=abc");
+        assert_eq!(
+            verify(SECRET, "/", Some(&odd), 0).unwrap_err(),
+            "malformed token"
+        );
+        let hex = format!("expires={expires}&token=zz");
+        assert_eq!(
+            verify(SECRET, "/", Some(&hex), 0).unwrap_err(),
+            "malformed token"
+        );
+    }
+
+    #[test]
+    fn params() {
+        assert_eq!(param(Some("a=1&delete&b=2"), "delete"), Some(""));
+        assert_eq!(param(Some("a=1&b=2"), "b"), Some("2"));
+        assert_eq!(param(Some("ab=1"), "a"), None);
+        assert_eq!(param(None, "a"), None);
+    }
+}
@@ -0,0 +1,227 @@
+//! Stores the body of a request as a file under the document root. The upload
+//! is first written to a temporary file in the target directory, which is then
+//! renamed into place, so that a partially written upload is never served.
+//!
+//! If the directive has been given a secret, every write must carry a valid
+//! token as described in the `token` module. This also enables deleting files,
+//! by sending a request without a body which carries a `delete` parameter along
+//! with the token.
+
+use {
+    super::{mime, token, Response},
+    crate::{
+        config::{Directive, Level, Server},
+        error::{RequestError, ServerError},
+        log::{anonymize, LogLevel},
+        request::Request,
+    },
+    std::{
+        ffi::OsStr,
+        fs::{self, Permissions},
+        io::{ErrorKind, Write},
+        os::unix::fs::PermissionsExt,
+        path::{Component, Path, PathBuf},
+    },
+    tempfile::NamedTempFile,
+};
+
+/// Whether `mimetype` matches any of the allowed patterns. A pattern may end in
+/// `/*` in order to match every subtype.
+fn allowed(mimetype: &str, patterns: &[String]) -> bool {
+    patterns.is_empty()
+        || patterns.iter().any(|p| match p.strip_suffix("/*") {
+            Some(prefix) => mimetype.split('/').next() == Some(prefix),
+            None => p == mimetype,
+        })
+}
+
+/// Whether the sniffed type of an upload agrees with the mimetype it's
+/// extension maps to. Text formats can't be told apart by their contents, so
+/// any text based type agrees with plain text, and any XML based type with XML.
+fn agrees(mapped: &str, sniffed: &str) -> bool {
+    let xml = mapped.ends_with("/xml") || mapped.ends_with("+xml");
+    let text = mapped.starts_with("text/")
+        || xml
+        || mapped.ends_with("/json")
+        || mapped.ends_with("+json");
+    mapped == sniffed || (sniffed == "text/plain" && text) || (sniffed == "application/xml" && xml)
+}
+
+/// The settings of an `Upload` directive
+pub struct Upload<'a> {
+    /// The directory to store uploads in, relative to the server root
+    pub target: &'a Path,
+    /// The maximum size of an upload in bytes
+    pub max_size: usize,
+    /// The mimetypes which may be uploaded
+    pub allowed_mimetypes: &'a [String],
+    /// Whether an upload may replace an existing file
+    pub overwrite: bool,
+    /// The secret used to verify write tokens, if writes require one
+    pub secret: Option<&'a str>,
+}
+
+impl<'a> Upload<'a> {
+    /// The settings of `directive`, if it is an `Upload` directive
+    #[must_use]
+    pub fn from_directive(directive: &'a Directive) -> Option<Self> {
+        match directive {
+            Directive::Upload {
+                dir,
+                max_size,
+                allowed_mimetypes,
+                overwrite,
+                secret,
+            } => Some(Self {
+                target: dir,
+                max_size: *max_size,
+                allowed_mimetypes,
+                overwrite: *overwrite,
+                secret: secret.as_deref(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Upload<'_> {
+    /// Whether this request is a write, which is either an upload carrying a
+    /// body or, if a secret has been configured, a deletion
+    #[must_use]
+    pub fn is_write(&self, request: &Request) -> bool {
+        request.content.is_some()
+            || (self.secret.is_some() && token::param(request.query.as_deref(), "delete").is_some())
+    }
+
+    /// Maps the request path onto the url path and file it refers to
+    fn locate(
+        &self,
+        request: &Request,
+        server: &Server,
+        dir: &Path,
+    ) -> Result<(PathBuf, PathBuf), Response> {
+        // The request path is known to begin with `dir`
+        let Ok(name) = Path::new(&request.path).strip_prefix(dir) else {
+            return Err(RequestError::InvalidPath.into());
+        };
+        if name.as_os_str().is_empty() {
+            return Err(RequestError::InvalidPath.into());
+        }
+        // Hidden files, such as `.meta` sidecars and headers, are trusted by
+        // the server and so may never be written by a client
+        let hidden = |c: &OsStr| c.as_encoded_bytes().starts_with(b".");
+        if !name
+            .components()
+            .all(|c| matches!(c, Component::Normal(c) if !hidden(c)))
+        {
+            return Err(ServerError::Unauthorized.into());
+        }
+        let url = PathBuf::from("/").join(self.target).join(name);
+        let mut path = server.root.clone();
+        path.push(url.strip_prefix("/").unwrap_or(&url));
+        Ok((url, path))
+    }
+
+    /// Performs the write requested by the client, after checking the token
+    /// carried in the query string if a secret has been configured
+    #[must_use]
+    pub fn handle(&self, request: Request, server: &Server, dir: &Path) -> Response {
+        if let Some(secret) = self.secret {
+            let query = request.query.as_deref();
+            if let Err(reason) = token::verify(secret, &request.path, query, request.length) {
+                let msg = format!(
+                    "Write token rejected for {}{} from {}: {reason}",
+                    server.name,
+                    request.path,
+                    anonymize::client_ip(request.client_ip)
+                );
+                if let Err(e) = msg.log_at(Level::Warn) {
+                    eprintln!("{e}");
+                }
+                return RequestError::InvalidToken.into();
+            }
+        }
+        let (url, path) = match self.locate(&request, server, dir) {
+            Ok(p) => p,
+            Err(r) => return r,
+        };
+        match request.content {
+            Some(body) => self.store(&body, &url, &path, server),
+            None => self.delete(&path),
+        }
+    }
+
+    /// Writes the request body to the file, and sends the client a redirect to
+    /// the new resource
+    fn store(&self, body: &[u8], url: &Path, path: &Path, server: &Server) -> Response {
+        if body.len() > self.max_size {
+            return RequestError::TooLarge.into();
+        }
+        // The type is sniffed from the contents, as the client picks the
+        // extension. If the extension maps to a type, which is what the file
+        // will be served as, the contents must agree with it.
+        let sniffed = tree_magic_mini::from_u8(body);
+        let mimetype = match mime::from_extension(path, server) {
+            Some(mapped) => {
+                // Any parameters in a configured mimetype are not part of the type
+                let mapped = match mapped.split_once(';') {
+                    Some((m, _)) => m.trim().to_string(),
+                    None => mapped,
+                };
+                if !agrees(&mapped, sniffed) {
+                    return RequestError::MimetypeNotAllowed(sniffed.to_string()).into();
+                }
+                mapped
+            }
+            None => sniffed.to_string(),
+        };
+        if !allowed(&mimetype, self.allowed_mimetypes) {
+            return RequestError::MimetypeNotAllowed(mimetype).into();
+        }
+        if path.exists() && !self.overwrite {
+            return RequestError::AlreadyExists.into();
+        }
+        let Some(parent) = path.parent() else {
+            return RequestError::InvalidPath.into();
+        };
+        let result = fs::create_dir_all(parent)
+            .and_then(|()| NamedTempFile::new_in(parent))
+            .and_then(|mut tmp| {
+                tmp.write_all(body)?;
+                tmp.as_file()
+                    .set_permissions(Permissions::from_mode(0o644))?;
+                if self.overwrite {
+                    tmp.persist(path)?;
+                } else {
+                    tmp.persist_noclobber(path)?;
+                }
+                Ok(())
+            });
+        match result {
+            Ok(()) => Response::Redirect {
+                target: url.to_string_lossy().to_string(),
+                permanent: false,
+            },
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => RequestError::AlreadyExists.into(),
+            Err(e) => Response::ServerError(e.into()),
+        }
+    }
+
+    /// Removes the file, and sends the client a redirect to the upload directory
+    fn delete(&self, path: &Path) -> Response {
+        if !path.is_file() {
+            return ServerError::NotFound.into();
+        }
+        match fs::remove_file(path) {
+            Ok(()) => Response::Redirect {
+                target: PathBuf::from("/")
+                    .join(self.target)
+                    .join("")
+                    .to_string_lossy()
+                    .to_string(),
+                permanent: false,
+            },
+            Err(e) => Response::ServerError(e.into()),
+        }
+    }
+}
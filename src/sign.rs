@@ -0,0 +1,30 @@
+//! Keyed hashes, used both to sign upload tokens and to anonymize client
+//! addresses
+
+use {
+    hmac::{Hmac, Mac},
+    sha2::Sha256,
+    std::fmt::Write as _,
+};
+
+pub type HmacSha256 = Hmac<Sha256>;
+
+/// Computes the HMAC-SHA256 of `message`, keyed with `key`. The result may be
+/// finalized, or verified against a signature.
+/// # Panics
+/// Does not panic, as Hmac accepts keys of any length
+#[must_use]
+pub fn hmac(key: &[u8], message: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(message);
+    mac
+}
+
+/// Encodes bytes as lower case hex
+#[must_use]
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        _ = write!(s, "{b:02x}");
+        s
+    })
+}
@@ -1,10 +1,17 @@
 use std::{
+    cell::Cell,
     num::NonZeroUsize,
-    sync::{mpsc, Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread,
 };
 
-use crate::log::{Log, LogError};
+use crate::{
+    config::Level,
+    log::{self, Log, LogError, LogLevel},
+};
 
 /// A pool of worker threads to handle requests
 pub struct ThreadPool {
@@ -12,6 +19,53 @@ pub struct ThreadPool {
     sender: mpsc::Sender<Message>,
 }
 
+thread_local! {
+    /// The id of the worker running on this thread
+    static WORKER_ID: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// The number of jobs waiting for a worker
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of workers running a job
+static BUSY: AtomicUsize = AtomicUsize::new(0);
+
+/// Gets the number of jobs waiting for a worker
+#[must_use]
+pub fn queued() -> usize {
+    QUEUED.load(Ordering::Relaxed)
+}
+
+/// Gets the number of workers running a job
+#[must_use]
+pub fn busy() -> usize {
+    BUSY.load(Ordering::Relaxed)
+}
+
+/// Counts a worker as running a job for as long as it is held, so that a job
+/// which panics is no longer counted
+struct Busy;
+
+impl Busy {
+    fn start() -> Self {
+        BUSY.fetch_add(1, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        BUSY.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Gets the id of the worker running on the current thread, or `None` if
+/// called from outside of the pool
+#[must_use]
+pub fn worker_id() -> Option<usize> {
+    WORKER_ID.with(Cell::get)
+}
+
 /// A type alias representing a job for the threadpool
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
@@ -22,13 +76,13 @@ enum Message {
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        println!("Sending terminate message to all workers");
+        let _msg = "Sending terminate message to all workers".log_at(Level::Debug);
         for _ in &self.workers {
             self.sender.send(Message::Terminate).unwrap();
         }
-        println!("Shutting down all workers");
+        let _msg = "Shutting down all workers".log_at(Level::Debug);
         for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+            let _msg = format!("Shutting down worker {}", worker.id).log_at(Level::Debug);
             if let Some(thread) = worker.thread.take() {
                 thread.join().unwrap();
             }
@@ -55,7 +109,9 @@ impl ThreadPool {
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(f);
+        QUEUED.fetch_add(1, Ordering::Relaxed);
         if let Err(e) = self.sender.send(Message::NewJob(job)) {
+            QUEUED.fetch_sub(1, Ordering::Relaxed);
             if let Err(e) = e.log_err() {
                 eprintln!("{e}");
             }
@@ -73,11 +129,14 @@ impl ThreadPool {
         }
         let _msg = "Shutting down all workers".to_string().log();
         for worker in &mut self.workers {
-            let _msg = format!("Dropping worker {}", worker.id).log();
+            let _msg = format!("Dropping worker {}", worker.id).log_at(Level::Debug);
             if let Some(thread) = worker.thread.take() {
                 thread.join().unwrap();
             }
         }
+        // Make sure that everything logged by the workers reaches the logs
+        // before the process exits
+        log::queue::flush();
     }
 }
 
@@ -90,30 +149,38 @@ pub struct Worker {
 impl Worker {
     /// Creates a new worker thread for the pool
     fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Self {
-        let thread = thread::spawn(move || loop {
-            // Using `try_lock` here causes misbehavior, at least with musl libc,
-            // as the call **thinks** it would block. This can fill the error log
-            // quickly with spam
-            match receiver.lock().map(|x| x.recv()) {
-                Err(e) => {
-                    if let Err(e) = e.log_err() {
-                        eprintln!("{e}");
-                    }
-                }
-                Ok(Err(e)) => {
-                    if let Err(e) = e.log_err() {
-                        eprintln!("{e}");
+        let thread = thread::spawn(move || {
+            WORKER_ID.with(|w| w.set(Some(id)));
+            loop {
+                // Using `try_lock` here causes misbehavior, at least with musl libc,
+                // as the call **thinks** it would block. This can fill the error log
+                // quickly with spam
+                match receiver.lock().map(|x| x.recv()) {
+                    Err(e) => {
+                        if let Err(e) = e.log_err() {
+                            eprintln!("{e}");
+                        }
                     }
-                }
-                Ok(Ok(message)) => match message {
-                    Message::NewJob(job) => job(),
-                    Message::Terminate => {
-                        if let Err(e) = format!("Worker {id} shutting down").log() {
+                    Ok(Err(e)) => {
+                        if let Err(e) = e.log_err() {
                             eprintln!("{e}");
                         }
-                        break;
                     }
-                },
+                    Ok(Ok(message)) => match message {
+                        Message::NewJob(job) => {
+                            QUEUED.fetch_sub(1, Ordering::Relaxed);
+                            let _busy = Busy::start();
+                            job();
+                        }
+                        Message::Terminate => {
+                            let msg = format!("Worker {id} shutting down");
+                            if let Err(e) = msg.log_at(Level::Debug) {
+                                eprintln!("{e}");
+                            }
+                            break;
+                        }
+                    },
+                }
             }
         });
         Self {